@@ -0,0 +1,307 @@
+use bevy::prelude::*;
+
+/* Fork of bevy-parallax 0.1.2 that scrolls vertically as well. Each layer is a row of
+copies of its texture, moved by `speed` (and `speed_y`) of the camera's movement: 1
+keeps the layer still on screen, as if infinitely far away, 0 moves it with the world.
+Copies that fall behind the camera are moved to the other end of the row, so layers
+repeat forever horizontally. Sheets with several cells play them as an animation.
+ */
+pub struct ParallaxPlugin;
+
+impl Plugin for ParallaxPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ParallaxResource>()
+            .add_event::<ParallaxMoveEvent>()
+            .add_startup_system(initialize_parallax_system)
+            .add_system(move_layers_system)
+            .add_system(animate_layers_system);
+    }
+}
+
+// window size used when there is no window, e.g. headless
+const DEFAULT_WINDOW_SIZE: (f32, f32) = (1280.0, 720.0);
+
+// seconds per cell of animated layers
+const FRAME_TIME: f32 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct LayerData {
+    // fraction of the camera's movement the layer follows
+    pub speed: f32,
+    pub speed_y: f32,
+    pub path: String,
+    // size of one cell of the texture
+    pub tile_size: Vec2,
+    pub cols: usize,
+    pub rows: usize,
+    pub scale: f32,
+    pub z: f32,
+    // how many layer widths from the camera a copy may get before it wraps around
+    pub transition_factor: f32,
+}
+
+impl Default for LayerData {
+    fn default() -> Self {
+        LayerData {
+            speed: 1.0,
+            speed_y: 1.0,
+            path: String::new(),
+            tile_size: Vec2::ZERO,
+            cols: 1,
+            rows: 1,
+            scale: 1.0,
+            z: 0.0,
+            transition_factor: 1.2,
+        }
+    }
+}
+
+impl LayerData {
+    /// Width of one copy of the layer in the world.
+    pub fn width(&self) -> f32 {
+        self.tile_size.x * self.scale
+    }
+
+    /// Copies needed to cover `window_width`, plus one on each side to wrap.
+    pub fn copies(&self, window_width: f32) -> usize {
+        if self.width() <= 0.0 {
+            return 1;
+        }
+        (window_width / self.width()).ceil() as usize + 2
+    }
+}
+
+#[derive(Default)]
+pub struct ParallaxResource {
+    pub layer_data: Vec<LayerData>,
+    pub layer_entities: Vec<Entity>,
+    pub window_size: Vec2,
+}
+
+impl ParallaxResource {
+    pub fn create_layers(
+        &mut self,
+        commands: &mut Commands,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) {
+        let window_width = self.window_size.x;
+        for layer in self.layer_data.iter() {
+            let texture_atlas = texture_atlases.add(TextureAtlas::from_grid(
+                asset_server.load(layer.path.as_str()),
+                layer.tile_size,
+                layer.cols.max(1),
+                layer.rows.max(1),
+            ));
+            let copies = layer.copies(window_width);
+            let width = layer.width();
+            let transform = Transform::from_xyz(0.0, 0.0, layer.z);
+            let mut entity = commands.spawn_bundle(TransformBundle::from_transform(transform));
+            entity.insert(LayerComponent {
+                speed: Vec2::new(layer.speed, layer.speed_y),
+                width,
+                copies,
+                transition_factor: layer.transition_factor,
+            });
+            entity.with_children(|parent| {
+                for copy in 0..copies {
+                    let x = (copy as f32 - (copies - 1) as f32 / 2.0) * width;
+                    let mut texture = parent.spawn_bundle(SpriteSheetBundle {
+                        texture_atlas: texture_atlas.clone(),
+                        transform: Transform::from_xyz(x, 0.0, 0.0).with_scale(Vec3::new(
+                            layer.scale,
+                            layer.scale,
+                            1.0,
+                        )),
+                        ..Default::default()
+                    });
+                    texture.insert(LayerTextureComponent);
+                    if layer.cols * layer.rows > 1 {
+                        texture.insert(LayerAnimation {
+                            frames: layer.cols * layer.rows,
+                            timer: Timer::from_seconds(FRAME_TIME, true),
+                        });
+                    }
+                }
+            });
+            let entity = entity.id();
+            self.layer_entities.push(entity);
+        }
+    }
+
+    pub fn despawn_layers(&mut self, commands: &mut Commands) {
+        for entity in self.layer_entities.drain(..) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Moves the parallax camera, and the layers with it.
+pub struct ParallaxMoveEvent {
+    pub camera_move_speed: f32,
+    pub camera_move_speed_y: f32,
+}
+
+impl ParallaxMoveEvent {
+    pub fn delta(&self) -> Vec2 {
+        Vec2::new(self.camera_move_speed, self.camera_move_speed_y)
+    }
+}
+
+#[derive(Component)]
+pub struct ParallaxCameraComponent;
+
+#[derive(Component)]
+pub struct LayerComponent {
+    pub speed: Vec2,
+    // width of one copy, and how many there are
+    pub width: f32,
+    pub copies: usize,
+    pub transition_factor: f32,
+}
+
+#[derive(Component)]
+pub struct LayerTextureComponent;
+
+#[derive(Component)]
+struct LayerAnimation {
+    frames: usize,
+    timer: Timer,
+}
+
+fn initialize_parallax_system(
+    mut commands: Commands,
+    windows: Option<Res<Windows>>,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    mut parallax: ResMut<ParallaxResource>,
+) {
+    parallax.window_size = windows
+        .and_then(|windows| {
+            windows
+                .get_primary()
+                .map(|window| Vec2::new(window.width(), window.height()))
+        })
+        .unwrap_or_else(|| Vec2::new(DEFAULT_WINDOW_SIZE.0, DEFAULT_WINDOW_SIZE.1));
+    parallax.create_layers(&mut commands, &asset_server, &mut texture_atlases);
+}
+
+fn move_layers_system(
+    mut events: EventReader<ParallaxMoveEvent>,
+    mut cameras: Query<&mut Transform, With<ParallaxCameraComponent>>,
+    mut layers: Query<
+        (&LayerComponent, &mut Transform, &Children),
+        Without<ParallaxCameraComponent>,
+    >,
+    mut textures: Query<
+        &mut Transform,
+        (
+            With<LayerTextureComponent>,
+            Without<LayerComponent>,
+            Without<ParallaxCameraComponent>,
+        ),
+    >,
+) {
+    let delta = events
+        .iter()
+        .fold(Vec2::ZERO, |delta, event| delta + event.delta());
+    if delta == Vec2::ZERO {
+        return;
+    }
+    let mut camera = match cameras.get_single_mut() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    camera.translation += delta.extend(0.0);
+    let camera_x = camera.translation.x;
+
+    for (layer, mut transform, children) in layers.iter_mut() {
+        transform.translation += (delta * layer.speed).extend(0.0);
+        let row_width = layer.width * layer.copies as f32;
+        for child in children.iter() {
+            let mut texture = match textures.get_mut(*child) {
+                Ok(texture) => texture,
+                Err(_) => continue,
+            };
+            // distance from the camera, in the world
+            let offset = transform.translation.x + texture.translation.x - camera_x;
+            if offset > layer.width * layer.transition_factor * layer.copies as f32 / 2.0 {
+                texture.translation.x -= row_width;
+            } else if offset < -layer.width * layer.transition_factor * layer.copies as f32 / 2.0 {
+                texture.translation.x += row_width;
+            }
+        }
+    }
+}
+
+fn animate_layers_system(
+    time: Res<Time>,
+    mut textures: Query<(&mut LayerAnimation, &mut TextureAtlasSprite)>,
+) {
+    for (mut animation, mut sprite) in textures.iter_mut() {
+        animation.timer.tick(time.delta());
+        if animation.timer.just_finished() {
+            sprite.index = (sprite.index + 1) % animation.frames;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+
+    use super::*;
+
+    #[test]
+    fn layers_cover_the_window_and_both_sides() {
+        let layer = LayerData {
+            tile_size: Vec2::new(272.0, 160.0),
+            scale: 4.5,
+            ..Default::default()
+        };
+        assert_eq!(layer.width(), 1224.0);
+        assert_eq!(layer.copies(1280.0), 4);
+        assert_eq!(LayerData::default().copies(1280.0), 1);
+    }
+
+    #[test]
+    fn layers_follow_their_speed_of_the_camera() {
+        let mut world = World::new();
+        world.insert_resource(Events::<ParallaxMoveEvent>::default());
+        let camera = world
+            .spawn()
+            .insert(Transform::default())
+            .insert(ParallaxCameraComponent)
+            .id();
+        let texture = world
+            .spawn()
+            .insert(Transform::default())
+            .insert(LayerTextureComponent)
+            .id();
+        let layer = world
+            .spawn()
+            .insert(Transform::default())
+            .insert(LayerComponent {
+                speed: Vec2::new(0.5, 0.25),
+                width: 100.0,
+                copies: 1,
+                transition_factor: 1.2,
+            })
+            .insert(Children::with(&[texture]))
+            .id();
+
+        world
+            .resource_mut::<Events<ParallaxMoveEvent>>()
+            .send(ParallaxMoveEvent {
+                camera_move_speed: 40.0,
+                camera_move_speed_y: 8.0,
+            });
+        let mut stage = SystemStage::single_threaded().with_system(move_layers_system);
+        stage.run(&mut world);
+
+        let translation = |entity| world.get::<Transform>(entity).unwrap().translation;
+        assert_eq!(translation(camera), Vec3::new(40.0, 8.0, 0.0));
+        assert_eq!(translation(layer), Vec3::new(20.0, 2.0, 0.0));
+        assert_eq!(translation(texture), Vec3::ZERO);
+    }
+}
@@ -0,0 +1,467 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use serde::Deserialize;
+
+use crate::character_controller::{aabb, CharacterController, ControllerSystem};
+use crate::collision::{Collider, CollisionGrid, TileKind};
+use crate::render_layers::Y_SORT_STEP;
+use crate::simulation::{
+    simulation_delta, simulation_delta_seconds, Position, SimulationStage, StorePreviousPositions,
+};
+
+pub struct AbilitiesPlugin;
+
+impl Plugin for AbilitiesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(
+            SimulationStage,
+            wall_and_ledge_abilities
+                .label(ControllerSystem::Abilities)
+                .after(StorePreviousPositions)
+                .before(ControllerSystem::Movement),
+        )
+        .add_system_to_stage(
+            SimulationStage,
+            dash_and_air_jump
+                .label(ControllerSystem::Abilities)
+                .after(wall_and_ledge_abilities)
+                .before(ControllerSystem::Movement),
+        )
+        .add_system_to_stage(SimulationStage, tick_invulnerability)
+        .add_system(fade_afterimages);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum Ability {
+    WallSlide,
+    WallJump,
+    LedgeGrab,
+    Dash,
+    DoubleJump,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LedgeHang {
+    pub side: f32,
+    pub ledge_top: f32,
+    // x of the wall face the character is hanging on
+    pub edge_x: f32,
+}
+
+pub struct Dash {
+    pub speed: f32,
+    pub duration: f32,
+    pub cooldown: f32,
+    // seconds of invulnerability granted when the dash starts
+    pub invulnerability: f32,
+    pub afterimage_interval: f32,
+    pub max_charges: u32,
+    pub charges: u32,
+    pub time_left: f32,
+    pub cooldown_left: f32,
+    afterimage_timer: f32,
+}
+
+impl Default for Dash {
+    fn default() -> Self {
+        Dash {
+            speed: 600.0,
+            duration: 0.15,
+            cooldown: 0.5,
+            invulnerability: 0.25,
+            afterimage_interval: 0.03,
+            max_charges: 1,
+            charges: 1,
+            time_left: 0.0,
+            cooldown_left: 0.0,
+            afterimage_timer: 0.0,
+        }
+    }
+}
+
+impl Dash {
+    pub fn is_dashing(&self) -> bool {
+        self.time_left > 0.0
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.charges > 0 && self.cooldown_left <= 0.0
+    }
+
+    // 0.0 when ready, 1.0 right after dashing; for cooldown widgets
+    pub fn cooldown_fraction(&self) -> f32 {
+        if self.cooldown <= 0.0 {
+            return 0.0;
+        }
+        (self.cooldown_left / self.cooldown).clamp(0.0, 1.0)
+    }
+}
+
+pub struct MultiJump {
+    // jumps allowed in the air, 1 for a double jump
+    pub max_air_jumps: u32,
+    pub air_jumps_left: u32,
+}
+
+impl Default for MultiJump {
+    fn default() -> Self {
+        MultiJump {
+            max_air_jumps: 1,
+            air_jumps_left: 1,
+        }
+    }
+}
+
+/// Traversal abilities of a character. Anything not unlocked is simply skipped,
+/// so levels can gate areas by handing out abilities.
+#[derive(Component)]
+pub struct Abilities {
+    unlocked: HashSet<Ability>,
+    pub wall_slide_speed: f32,
+    pub wall_jump_push: f32,
+    // seconds the push away from the wall overrides movement input
+    pub wall_jump_lock: f32,
+    // how far above the head a ledge can still be grabbed
+    pub ledge_reach: f32,
+    pub hanging: Option<LedgeHang>,
+    pub dash: Dash,
+    pub multi_jump: MultiJump,
+}
+
+impl Default for Abilities {
+    fn default() -> Self {
+        Abilities {
+            unlocked: HashSet::default(),
+            wall_slide_speed: 80.0,
+            wall_jump_push: 260.0,
+            wall_jump_lock: 0.15,
+            ledge_reach: 8.0,
+            hanging: None,
+            dash: Dash::default(),
+            multi_jump: MultiJump::default(),
+        }
+    }
+}
+
+impl Abilities {
+    pub fn with(abilities: &[Ability]) -> Self {
+        let mut result = Abilities::default();
+        for ability in abilities {
+            result.unlock(*ability);
+        }
+        result
+    }
+
+    pub fn unlock(&mut self, ability: Ability) {
+        self.unlocked.insert(ability);
+    }
+
+    pub fn has(&self, ability: Ability) -> bool {
+        self.unlocked.contains(&ability)
+    }
+}
+
+pub fn wall_and_ledge_abilities(
+    grid: Res<CollisionGrid>,
+    mut query: Query<(
+        &mut Abilities,
+        &mut CharacterController,
+        &mut Position,
+        &Collider,
+        Option<&mut TextureAtlasSprite>,
+    )>,
+) {
+    for (mut abilities, mut controller, mut position, collider, sprite) in query.iter_mut() {
+        if let Some(hang) = abilities.hanging {
+            if controller.jump_requested || controller.vertical_input > 0.0 {
+                // climb up onto the ledge
+                position.current = Vec2::new(
+                    hang.edge_x + hang.side * (collider.half_size.x + 1.0),
+                    hang.ledge_top + collider.half_size.y,
+                );
+                controller.jump_requested = false;
+                release_ledge(&mut abilities, &mut controller);
+            } else if controller.vertical_input < 0.0 || controller.move_direction == -hang.side {
+                release_ledge(&mut abilities, &mut controller);
+            } else {
+                position.velocity = Vec2::ZERO;
+                controller.move_direction = 0.0;
+            }
+            continue;
+        }
+
+        if controller.grounded {
+            continue;
+        }
+        let wall = match controller.wall {
+            Some(wall) => wall,
+            None => continue,
+        };
+        let pressing_wall = controller.move_direction == wall;
+
+        if abilities.has(Ability::WallJump) && controller.jump_requested {
+            position.velocity.x = -wall * abilities.wall_jump_push;
+            position.velocity.y = controller.jump_speed;
+            controller.input_lock = abilities.wall_jump_lock;
+            controller.jump_requested = false;
+            controller.facing = -wall;
+            if let Some(mut sprite) = sprite {
+                sprite.flip_x = wall > 0.0;
+            }
+            continue;
+        }
+
+        if abilities.has(Ability::LedgeGrab) && pressing_wall && position.velocity.y <= 0.0 {
+            if let Some(hang) = find_ledge(
+                &grid,
+                position.current,
+                collider,
+                wall,
+                abilities.ledge_reach,
+            ) {
+                position.current.y = hang.ledge_top - collider.half_size.y;
+                position.velocity = Vec2::ZERO;
+                controller.gravity_scale = 0.0;
+                abilities.hanging = Some(hang);
+                continue;
+            }
+        }
+
+        if abilities.has(Ability::WallSlide) && pressing_wall {
+            controller.fall_speed_limit = Some(abilities.wall_slide_speed);
+        }
+    }
+}
+
+pub fn dash_and_air_jump(
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &mut Abilities,
+        &mut CharacterController,
+        &mut Position,
+        &Transform,
+        Option<(&TextureAtlasSprite, &Handle<TextureAtlas>)>,
+    )>,
+) {
+    let dt = simulation_delta_seconds();
+    for (entity, mut abilities, mut controller, mut position, transform, sprite) in query.iter_mut()
+    {
+        let abilities = &mut *abilities;
+        let dash = &mut abilities.dash;
+        dash.cooldown_left = (dash.cooldown_left - dt).max(0.0);
+
+        // charges come back on landing
+        if controller.grounded && !dash.is_dashing() {
+            dash.charges = dash.max_charges;
+            abilities.multi_jump.air_jumps_left = abilities.multi_jump.max_air_jumps;
+        }
+
+        if dash.is_dashing() {
+            dash.time_left -= dt;
+            dash.afterimage_timer -= dt;
+            if dash.afterimage_timer <= 0.0 {
+                dash.afterimage_timer = dash.afterimage_interval;
+                if let Some((sprite, atlas)) = sprite {
+                    spawn_afterimage(&mut commands, transform, sprite, atlas);
+                }
+            }
+            // a ledge grabbed mid-dash keeps holding the character up
+            if !dash.is_dashing() && abilities.hanging.is_none() {
+                controller.gravity_scale = 1.0;
+                position.velocity.y = position.velocity.y.min(0.0);
+            }
+            continue;
+        }
+
+        let can_dash = abilities.unlocked.contains(&Ability::Dash)
+            && abilities.hanging.is_none()
+            && dash.is_ready();
+        if controller.dash_requested && can_dash {
+            let mut direction = Vec2::new(controller.move_direction, controller.vertical_input);
+            if direction == Vec2::ZERO {
+                direction.x = controller.facing;
+            }
+            position.velocity = direction.normalize() * dash.speed;
+            controller.gravity_scale = 0.0;
+            controller.input_lock = dash.duration;
+            dash.time_left = dash.duration;
+            dash.afterimage_timer = 0.0;
+            dash.charges -= 1;
+            dash.cooldown_left = dash.cooldown;
+            commands
+                .entity(entity)
+                .insert(Invulnerable(Timer::from_seconds(
+                    dash.invulnerability,
+                    false,
+                )));
+            continue;
+        }
+
+        let multi_jump = &mut abilities.multi_jump;
+        if controller.jump_requested
+            && !controller.grounded
+            && abilities.hanging.is_none()
+            && abilities.unlocked.contains(&Ability::DoubleJump)
+            && multi_jump.air_jumps_left > 0
+        {
+            multi_jump.air_jumps_left -= 1;
+            position.velocity.y = controller.jump_speed;
+            controller.jump_requested = false;
+        }
+    }
+}
+
+/// Ignores incoming damage until the timer runs out.
+#[derive(Component, Deref, DerefMut)]
+pub struct Invulnerable(pub Timer);
+
+fn tick_invulnerability(mut commands: Commands, mut query: Query<(Entity, &mut Invulnerable)>) {
+    for (entity, mut invulnerable) in query.iter_mut() {
+        if invulnerable.tick(simulation_delta()).finished() {
+            commands.entity(entity).remove::<Invulnerable>();
+        }
+    }
+}
+
+#[derive(Component, Deref, DerefMut)]
+struct Afterimage(Timer);
+
+fn spawn_afterimage(
+    commands: &mut Commands,
+    transform: &Transform,
+    sprite: &TextureAtlasSprite,
+    atlas: &Handle<TextureAtlas>,
+) {
+    let mut transform = *transform;
+    transform.translation.z -= Y_SORT_STEP * 0.5;
+    commands
+        .spawn_bundle(SpriteSheetBundle {
+            sprite: TextureAtlasSprite {
+                index: sprite.index,
+                flip_x: sprite.flip_x,
+                color: Color::rgba(0.6, 0.8, 1.0, 0.6),
+                ..Default::default()
+            },
+            texture_atlas: atlas.clone(),
+            transform,
+            ..Default::default()
+        })
+        .insert(Afterimage(Timer::from_seconds(0.25, false)));
+}
+
+fn fade_afterimages(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(Entity, &mut Afterimage, &mut TextureAtlasSprite)>,
+) {
+    for (entity, mut afterimage, mut sprite) in query.iter_mut() {
+        afterimage.tick(time.delta());
+        if afterimage.finished() {
+            commands.entity(entity).despawn();
+        } else {
+            sprite.color.set_a(0.6 * (1.0 - afterimage.percent()));
+        }
+    }
+}
+
+fn release_ledge(abilities: &mut Abilities, controller: &mut CharacterController) {
+    abilities.hanging = None;
+    controller.gravity_scale = 1.0;
+}
+
+// A ledge is a solid cell next to the head with free space above it for the whole body.
+fn find_ledge(
+    grid: &CollisionGrid,
+    position: Vec2,
+    collider: &Collider,
+    side: f32,
+    reach: f32,
+) -> Option<LedgeHang> {
+    let (min, max) = aabb(position, collider);
+    let probe_x = if side < 0.0 { min.x - 1.0 } else { max.x + 1.0 };
+    let (cell_x, top_row) = grid.cell_at(Vec2::new(probe_x, max.y + reach));
+    let (_, bottom_row) = grid.cell_at(Vec2::new(probe_x, position.y));
+
+    for row in top_row..=bottom_row {
+        if !grid.tile(cell_x, row).is_solid() || grid.tile(cell_x, row - 1) != TileKind::Empty {
+            continue;
+        }
+        let (cell_min, cell_max) = grid.cell_rect(cell_x, row);
+        let ledge_top = cell_max.y;
+        if ledge_top < position.y || ledge_top > max.y + reach {
+            continue;
+        }
+
+        let edge_x = if side < 0.0 { cell_max.x } else { cell_min.x };
+        let climb_x = edge_x + side * (collider.half_size.x + 1.0);
+        let room_min = Vec2::new(climb_x - collider.half_size.x, ledge_top + 0.01);
+        let room_max = room_min + collider.half_size * 2.0 - Vec2::new(0.0, 0.02);
+        if grid.solid_cells_in(room_min, room_max).is_empty() {
+            return Some(LedgeHang {
+                side,
+                ledge_top,
+                edge_x,
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character_controller::move_characters;
+    use crate::collision::SurfaceMaterials;
+
+    #[test]
+    fn dashing_into_a_ledge_hangs_from_it() {
+        // a wall on the right, its top a ledge by the character's head
+        let csv = "\
+            0,0,0,0\n\
+            0,0,0,1\n\
+            0,0,0,1\n\
+            0,0,0,1\n";
+        let mut world = World::new();
+        world.insert_resource(CollisionGrid::from_csv(csv, 16.0, Vec2::ZERO));
+        world.insert_resource(SurfaceMaterials::default());
+        let entity = world
+            .spawn()
+            .insert(Position::new(Vec2::new(24.0, -22.0)))
+            .insert(CharacterController {
+                move_direction: 1.0,
+                dash_requested: true,
+                ..Default::default()
+            })
+            .insert(Collider {
+                half_size: Vec2::new(4.0, 8.0),
+            })
+            .insert(Abilities::with(&[Ability::Dash, Ability::LedgeGrab]))
+            .insert(Transform::default())
+            .id();
+
+        let mut abilities = SystemStage::single_threaded().with_system(wall_and_ledge_abilities);
+        let mut dash = SystemStage::single_threaded().with_system(dash_and_air_jump);
+        let mut movement = SystemStage::single_threaded().with_system(move_characters);
+        // well past the end of the dash
+        for _ in 0..30 {
+            abilities.run(&mut world);
+            dash.run(&mut world);
+            movement.run(&mut world);
+        }
+
+        let abilities = world.get::<Abilities>(entity).unwrap();
+        assert!(!abilities.dash.is_dashing());
+        assert!(abilities.hanging.is_some());
+        assert_eq!(
+            world
+                .get::<CharacterController>(entity)
+                .unwrap()
+                .gravity_scale,
+            0.0
+        );
+        // the ledge's top is level with the top of the body
+        assert_eq!(world.get::<Position>(entity).unwrap().current.y, -24.0);
+    }
+}
@@ -3,6 +3,10 @@ use bevy::{
     prelude::*,
 };
 
+use crate::atlas::validate_animation;
+use crate::render_layers::RenderLayer;
+use crate::simulation::{simulation_delta_seconds, Position, SimulationStage};
+
 #[derive(Component, Deref, DerefMut)]
 struct Player {
     speed: f32,
@@ -20,13 +24,18 @@ impl Plugin for AnimatedSpritePlugin {
         app.add_startup_system(setup)
             .insert_resource(Transform::default())
             .add_system(animate_sprite)
-            .add_system(move_sprite);
+            .add_system_to_stage(SimulationStage, move_sprite);
     }
 }
 
 pub struct KeyBinds {
     pub move_left: KeyCode,
     pub move_right: KeyCode,
+    pub move_up: KeyCode,
+    pub move_down: KeyCode,
+    pub jump: KeyCode,
+    pub dash: KeyCode,
+    pub interact: KeyCode,
 }
 
 impl Default for KeyBinds {
@@ -34,6 +43,11 @@ impl Default for KeyBinds {
         KeyBinds {
             move_left: KeyCode::A,
             move_right: KeyCode::D,
+            move_up: KeyCode::W,
+            move_down: KeyCode::S,
+            jump: KeyCode::Space,
+            dash: KeyCode::LShift,
+            interact: KeyCode::E,
         }
     }
 }
@@ -46,15 +60,16 @@ fn setup(
     let texture_handle = asset_server.load("npcs/Warrior_Sheet-Effect.png");
     let texture_atlas = TextureAtlas::from_grid(texture_handle, Vec2::new(69.0, 44.0), 6, 17);
     let texture_atlas_handle = texture_atlases.add(texture_atlas);
-    
+
     commands.spawn_bundle(OrthographicCameraBundle::new_2d());
     commands
         .spawn_bundle(SpriteSheetBundle {
             texture_atlas: texture_atlas_handle,
-            transform: Transform::from_xyz(100.0, 100.0, 10.0).with_scale(Vec3::splat(2.0)),
+            transform: Transform::from_xyz(100.0, 100.0, RenderLayer::Entities.z(10.0)).with_scale(Vec3::splat(2.0)),
             ..Default::default()
         })
         .insert(Player { speed: 200.0 })
+        .insert(Position::new(Vec2::new(100.0, 100.0)))
         .insert(SpriteMeta { flip: false })
         .insert(AnimationTimer(Timer::from_seconds(0.2, true)));
 }
@@ -62,17 +77,19 @@ fn setup(
 #[derive(Component, Deref, DerefMut)]
 struct AnimationTimer(Timer);
 
+// first and last frame of the walk cycle on `Warrior_Sheet-Effect.png`
+const WARRIOR_WALK_FRAMES: (usize, usize) = (6, 13);
+
 fn move_sprite(
-    time: Res<Time>,
     input: Res<Input<KeyCode>>,
     mut query: Query<(
         &Player,
         &mut SpriteMeta,
-        &mut Transform,
+        &mut Position,
         &mut TextureAtlasSprite,
     )>,
 ) {
-    for (player, mut sprite_info, mut transform, mut sprite) in query.iter_mut() {
+    for (player, mut sprite_info, mut position, mut sprite) in query.iter_mut() {
         // grab keybinds (unnecessary once I can load it as an asset)
         let keybinds = KeyBinds {
             ..Default::default()
@@ -96,11 +113,10 @@ fn move_sprite(
         sprite_info.flip = flip;
 
         // Calculate and apply movement
-        let delta = time.delta_seconds() * player.speed;
-        let translation = &mut transform.translation;
+        let delta = simulation_delta_seconds() * player.speed;
 
         sprite.flip_x = sprite_info.flip;
-        translation.x += direction * delta;
+        position.current.x += direction * delta;
     }
 }
 
@@ -116,9 +132,19 @@ fn animate_sprite(
     for (mut timer, mut sprite, texture_atlas_handle) in query.iter_mut() {
         timer.tick(time.delta());
         if timer.just_finished() {
-            let _texture_atlas = texture_atlases.get(texture_atlas_handle).unwrap();
-            // sprite.index = (sprite.index + 1) % texture_atlas.textures.len();
-            sprite.index = ((sprite.index + 1) % 8) + 6;
+            let texture_atlas = texture_atlases.get(texture_atlas_handle).unwrap();
+            let (first, last) = WARRIOR_WALK_FRAMES;
+            if let Err(error) =
+                validate_animation("warrior-walk", first, last, texture_atlas.textures.len())
+            {
+                error!("{}", error);
+                continue;
+            }
+            sprite.index = if sprite.index < first || sprite.index >= last {
+                first
+            } else {
+                sprite.index + 1
+            };
         }
     }
 }
@@ -0,0 +1,755 @@
+use std::io::Read;
+
+use anyhow::{bail, ensure};
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use flate2::read::ZlibDecoder;
+
+use crate::atlas::{grid_atlas, AtlasGrid};
+
+pub struct AsepritePlugin;
+
+impl Plugin for AsepritePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Aseprite>()
+            .init_asset_loader::<AsepriteLoader>();
+    }
+}
+
+/* A sprite loaded straight from an Aseprite file. Every frame is flattened (visible
+layers only, normal blending) into one sheet image, available as `<file>.ase#texture`,
+with a texture atlas over it as `<file>.ase#atlas`; atlas index n is frame n.
+Format reference: https://github.com/aseprite/aseprite/blob/main/docs/ase-file-specs.md
+ */
+#[derive(TypeUuid)]
+#[uuid = "c2a6f1d4-7e3b-4b8a-9f25-3d8e0b6a1c47"]
+pub struct Aseprite {
+    pub size: UVec2,
+    pub layers: Vec<AsepriteLayer>,
+    pub frames: Vec<AsepriteFrame>,
+    pub tags: Vec<AsepriteTag>,
+    pub slices: Vec<AsepriteSlice>,
+    pub texture: Handle<Image>,
+    pub atlas: Handle<TextureAtlas>,
+}
+
+impl Aseprite {
+    pub fn tag(&self, name: &str) -> Option<&AsepriteTag> {
+        self.tags.iter().find(|tag| tag.name == name)
+    }
+
+    pub fn slice(&self, name: &str) -> Option<&AsepriteSlice> {
+        self.slices.iter().find(|slice| slice.name == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerKind {
+    Image,
+    Group,
+    Tilemap,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsepriteLayer {
+    pub name: String,
+    pub kind: LayerKind,
+    pub visible: bool,
+    // depth in the layer tree, 0 for top-level layers
+    pub child_level: u16,
+    pub opacity: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AsepriteFrame {
+    // seconds
+    pub duration: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagDirection {
+    Forward,
+    Reverse,
+    PingPong,
+    PingPongReverse,
+}
+
+#[derive(Debug, Clone)]
+pub struct AsepriteTag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    pub direction: TagDirection,
+    // 0 plays forever
+    pub repeat: u16,
+}
+
+impl AsepriteTag {
+    /// Frame indices of one pass through the tag, in play order.
+    pub fn frame_sequence(&self) -> Vec<usize> {
+        let forward: Vec<usize> = (self.from..=self.to).collect();
+        let reverse: Vec<usize> = forward.iter().rev().copied().collect();
+        match self.direction {
+            TagDirection::Forward => forward,
+            TagDirection::Reverse => reverse,
+            // the turning frames are not repeated
+            TagDirection::PingPong => {
+                let back = reverse.iter().skip(1).take(forward.len().saturating_sub(2));
+                forward.iter().chain(back).copied().collect()
+            }
+            TagDirection::PingPongReverse => {
+                let back = forward.iter().skip(1).take(reverse.len().saturating_sub(2));
+                reverse.iter().chain(back).copied().collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AsepriteSlice {
+    pub name: String,
+    pub keys: Vec<SliceKey>,
+}
+
+/// A slice's bounds from `frame` onwards, until the next key.
+#[derive(Debug, Clone, Copy)]
+pub struct SliceKey {
+    pub frame: usize,
+    pub position: IVec2,
+    pub size: UVec2,
+    // nine-patch center, relative to the slice
+    pub center: Option<(IVec2, UVec2)>,
+    pub pivot: Option<IVec2>,
+}
+
+#[derive(Default)]
+pub struct AsepriteLoader;
+
+impl AssetLoader for AsepriteLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let file = AseFile::parse(bytes)?;
+            let frame_count = file.frames.len();
+            let columns = (frame_count as f32).sqrt().ceil().max(1.0) as usize;
+            let rows = ((frame_count + columns - 1) / columns).max(1);
+
+            let (width, height) = (file.width as usize, file.height as usize);
+            let sheet_width = width * columns;
+            let mut sheet = vec![0u8; sheet_width * height * rows * 4];
+            for index in 0..frame_count {
+                let pixels = file.composite(index);
+                let (x, y) = ((index % columns) * width, (index / columns) * height);
+                for row in 0..height {
+                    let start = ((y + row) * sheet_width + x) * 4;
+                    sheet[start..start + width * 4]
+                        .copy_from_slice(&pixels[row * width * 4..(row + 1) * width * 4]);
+                }
+            }
+
+            let image = Image::new(
+                Extent3d {
+                    width: sheet_width as u32,
+                    height: (height * rows) as u32,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                sheet,
+                TextureFormat::Rgba8UnormSrgb,
+            );
+            let texture = load_context.set_labeled_asset("texture", LoadedAsset::new(image));
+            let grid = AtlasGrid {
+                cell_size: Vec2::new(width as f32, height as f32),
+                columns,
+                rows,
+            };
+            let atlas = load_context.set_labeled_asset(
+                "atlas",
+                LoadedAsset::new(grid_atlas(texture.clone(), &grid, frame_count)),
+            );
+
+            load_context.set_default_asset(LoadedAsset::new(Aseprite {
+                size: UVec2::new(file.width as u32, file.height as u32),
+                layers: file.layers,
+                frames: file
+                    .frames
+                    .iter()
+                    .map(|frame| AsepriteFrame {
+                        duration: frame.duration as f32 / 1000.0,
+                    })
+                    .collect(),
+                tags: file.tags,
+                slices: file.slices,
+                texture,
+                atlas,
+            }));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ase", "aseprite"]
+    }
+}
+
+const HEADER_MAGIC: u16 = 0xA5E0;
+const FRAME_MAGIC: u16 = 0xF1FA;
+
+const CHUNK_OLD_PALETTE: u16 = 0x0004;
+const CHUNK_LAYER: u16 = 0x2004;
+const CHUNK_CEL: u16 = 0x2005;
+const CHUNK_TAGS: u16 = 0x2018;
+const CHUNK_PALETTE: u16 = 0x2019;
+const CHUNK_SLICE: u16 = 0x2022;
+
+const LAYER_VISIBLE: u16 = 1;
+const LAYER_BACKGROUND: u16 = 8;
+const LAYER_REFERENCE: u16 = 64;
+// header flag: the layer opacity field is valid
+const HEADER_LAYER_OPACITY: u32 = 1;
+
+struct AseFile {
+    width: u16,
+    height: u16,
+    layers: Vec<AsepriteLayer>,
+    layer_flags: Vec<u16>,
+    frames: Vec<AseFrame>,
+    tags: Vec<AsepriteTag>,
+    slices: Vec<AsepriteSlice>,
+}
+
+struct AseFrame {
+    // milliseconds
+    duration: u16,
+    cels: Vec<AseCel>,
+}
+
+struct AseCel {
+    layer: usize,
+    position: IVec2,
+    opacity: u8,
+    z_index: i16,
+    content: CelContent,
+}
+
+enum CelContent {
+    Image { size: UVec2, rgba: Vec<u8> },
+    // shares the image of the same layer's cel in another frame
+    Linked(usize),
+    // tilemap cels need the tileset, which is not supported yet
+    Tilemap,
+}
+
+impl AseFile {
+    fn parse(bytes: &[u8]) -> anyhow::Result<AseFile> {
+        let mut header = Reader::new(bytes);
+        header.skip(4)?;
+        ensure!(header.word()? == HEADER_MAGIC, "not an Aseprite file");
+        let frame_count = header.word()?;
+        let width = header.word()?;
+        let height = header.word()?;
+        let color_depth = header.word()?;
+        let flags = header.dword()?;
+        header.skip(10)?;
+        let transparent_index = header.byte()?;
+        ensure!(
+            matches!(color_depth, 8 | 16 | 32),
+            "unsupported color depth {}",
+            color_depth
+        );
+
+        let mut file = AseFile {
+            width,
+            height,
+            layers: Vec::new(),
+            layer_flags: Vec::new(),
+            frames: Vec::new(),
+            tags: Vec::new(),
+            slices: Vec::new(),
+        };
+        let mut palette = vec![[0u8; 4]; 256];
+        let mut offset = 128;
+
+        for frame_index in 0..frame_count as usize {
+            let mut reader = Reader::new(&bytes[offset.min(bytes.len())..]);
+            let frame_size = reader.dword()? as usize;
+            ensure!(
+                reader.word()? == FRAME_MAGIC,
+                "frame {} is corrupted",
+                frame_index
+            );
+            let old_chunk_count = reader.word()?;
+            let duration = reader.word()?;
+            reader.skip(2)?;
+            let chunk_count = match reader.dword()? {
+                0 => old_chunk_count as u32,
+                count => count,
+            };
+            let mut frame = AseFrame {
+                duration,
+                cels: Vec::new(),
+            };
+
+            for _ in 0..chunk_count {
+                let chunk_size = reader.dword()? as usize;
+                ensure!(
+                    chunk_size >= 6,
+                    "chunk in frame {} is corrupted",
+                    frame_index
+                );
+                let chunk_type = reader.word()?;
+                let mut chunk = Reader::new(reader.take(chunk_size - 6)?);
+                match chunk_type {
+                    CHUNK_OLD_PALETTE => read_old_palette(&mut chunk, &mut palette)?,
+                    CHUNK_PALETTE => read_palette(&mut chunk, &mut palette)?,
+                    CHUNK_LAYER => {
+                        let layer_flags = chunk.word()?;
+                        let kind = match chunk.word()? {
+                            0 => LayerKind::Image,
+                            1 => LayerKind::Group,
+                            _ => LayerKind::Tilemap,
+                        };
+                        let child_level = chunk.word()?;
+                        chunk.skip(6)?;
+                        let opacity = chunk.byte()?;
+                        chunk.skip(3)?;
+                        let name = chunk.string()?;
+                        file.layers.push(AsepriteLayer {
+                            name,
+                            kind,
+                            visible: layer_flags & LAYER_VISIBLE != 0,
+                            child_level,
+                            opacity: if flags & HEADER_LAYER_OPACITY != 0 {
+                                opacity
+                            } else {
+                                255
+                            },
+                        });
+                        file.layer_flags.push(layer_flags);
+                    }
+                    CHUNK_CEL => {
+                        let layer = chunk.word()? as usize;
+                        let position = IVec2::new(chunk.short()? as i32, chunk.short()? as i32);
+                        let opacity = chunk.byte()?;
+                        let cel_type = chunk.word()?;
+                        let z_index = chunk.short()?;
+                        chunk.skip(5)?;
+                        let background = file
+                            .layer_flags
+                            .get(layer)
+                            .map_or(false, |flags| flags & LAYER_BACKGROUND != 0);
+                        let content = match cel_type {
+                            0 | 2 => {
+                                let size = UVec2::new(chunk.word()? as u32, chunk.word()? as u32);
+                                let data = if cel_type == 0 {
+                                    chunk.rest().to_vec()
+                                } else {
+                                    let mut data = Vec::new();
+                                    ZlibDecoder::new(chunk.rest()).read_to_end(&mut data)?;
+                                    data
+                                };
+                                let rgba = to_rgba(
+                                    &data,
+                                    size,
+                                    color_depth,
+                                    &palette,
+                                    (!background).then(|| transparent_index),
+                                )?;
+                                CelContent::Image { size, rgba }
+                            }
+                            1 => CelContent::Linked(chunk.word()? as usize),
+                            _ => CelContent::Tilemap,
+                        };
+                        frame.cels.push(AseCel {
+                            layer,
+                            position,
+                            opacity,
+                            z_index,
+                            content,
+                        });
+                    }
+                    CHUNK_TAGS => {
+                        let count = chunk.word()?;
+                        chunk.skip(8)?;
+                        for _ in 0..count {
+                            let from = chunk.word()? as usize;
+                            let to = chunk.word()? as usize;
+                            let direction = match chunk.byte()? {
+                                1 => TagDirection::Reverse,
+                                2 => TagDirection::PingPong,
+                                3 => TagDirection::PingPongReverse,
+                                _ => TagDirection::Forward,
+                            };
+                            let repeat = chunk.word()?;
+                            chunk.skip(10)?;
+                            let name = chunk.string()?;
+                            file.tags.push(AsepriteTag {
+                                name,
+                                from,
+                                to,
+                                direction,
+                                repeat,
+                            });
+                        }
+                    }
+                    CHUNK_SLICE => {
+                        let key_count = chunk.dword()?;
+                        let slice_flags = chunk.dword()?;
+                        chunk.skip(4)?;
+                        let name = chunk.string()?;
+                        let mut keys = Vec::new();
+                        for _ in 0..key_count {
+                            let frame = chunk.dword()? as usize;
+                            let position = IVec2::new(chunk.long()?, chunk.long()?);
+                            let size = UVec2::new(chunk.dword()?, chunk.dword()?);
+                            let center = if slice_flags & 1 != 0 {
+                                Some((
+                                    IVec2::new(chunk.long()?, chunk.long()?),
+                                    UVec2::new(chunk.dword()?, chunk.dword()?),
+                                ))
+                            } else {
+                                None
+                            };
+                            let pivot = if slice_flags & 2 != 0 {
+                                Some(IVec2::new(chunk.long()?, chunk.long()?))
+                            } else {
+                                None
+                            };
+                            keys.push(SliceKey {
+                                frame,
+                                position,
+                                size,
+                                center,
+                                pivot,
+                            });
+                        }
+                        file.slices.push(AsepriteSlice { name, keys });
+                    }
+                    // color profile, user data, tilesets...
+                    _ => {}
+                }
+            }
+
+            file.frames.push(frame);
+            offset += frame_size;
+        }
+
+        Ok(file)
+    }
+
+    /// Flattens the visible layers of a frame into a `width * height` RGBA image.
+    fn composite(&self, frame: usize) -> Vec<u8> {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let mut pixels = vec![0u8; (width * height * 4) as usize];
+
+        // a layer is only drawn if all the groups it is in are visible too
+        let mut visible = vec![true; self.layers.len()];
+        let mut parents: Vec<bool> = Vec::new();
+        for (index, layer) in self.layers.iter().enumerate() {
+            parents.truncate(layer.child_level as usize);
+            let shown = layer.visible
+                && parents.iter().all(|parent| *parent)
+                && self.layer_flags[index] & LAYER_REFERENCE == 0;
+            visible[index] = shown;
+            parents.push(shown);
+        }
+
+        let mut cels: Vec<&AseCel> = self.frames[frame]
+            .cels
+            .iter()
+            .filter(|cel| visible.get(cel.layer).copied().unwrap_or(false))
+            .filter(|cel| self.layers[cel.layer].kind == LayerKind::Image)
+            .collect();
+        cels.sort_by_key(|cel| (cel.layer as i32 + cel.z_index as i32, cel.z_index));
+
+        for cel in cels {
+            let (size, rgba) = match self.cel_image(cel) {
+                Some(image) => image,
+                None => continue,
+            };
+            let opacity =
+                cel.opacity as f32 / 255.0 * self.layers[cel.layer].opacity as f32 / 255.0;
+            for y in 0..size.y as i32 {
+                for x in 0..size.x as i32 {
+                    let (target_x, target_y) = (cel.position.x + x, cel.position.y + y);
+                    if target_x < 0 || target_y < 0 || target_x >= width || target_y >= height {
+                        continue;
+                    }
+                    let source = ((y * size.x as i32 + x) * 4) as usize;
+                    let target = ((target_y * width + target_x) * 4) as usize;
+                    blend(
+                        &mut pixels[target..target + 4],
+                        &rgba[source..source + 4],
+                        opacity,
+                    );
+                }
+            }
+        }
+        pixels
+    }
+
+    fn cel_image<'a>(&'a self, cel: &'a AseCel) -> Option<(UVec2, &'a [u8])> {
+        match &cel.content {
+            CelContent::Image { size, rgba } => Some((*size, rgba)),
+            CelContent::Linked(frame) => self
+                .frames
+                .get(*frame)?
+                .cels
+                .iter()
+                .find(|linked| linked.layer == cel.layer)
+                .and_then(|linked| match &linked.content {
+                    CelContent::Image { size, rgba } => Some((*size, rgba.as_slice())),
+                    _ => None,
+                }),
+            CelContent::Tilemap => None,
+        }
+    }
+}
+
+// normal blend mode, straight alpha
+fn blend(target: &mut [u8], source: &[u8], opacity: f32) {
+    let source_alpha = source[3] as f32 / 255.0 * opacity;
+    if source_alpha <= 0.0 {
+        return;
+    }
+    let target_alpha = target[3] as f32 / 255.0;
+    let alpha = source_alpha + target_alpha * (1.0 - source_alpha);
+    for channel in 0..3 {
+        let mixed = (source[channel] as f32 * source_alpha
+            + target[channel] as f32 * target_alpha * (1.0 - source_alpha))
+            / alpha;
+        target[channel] = mixed.round() as u8;
+    }
+    target[3] = (alpha * 255.0).round() as u8;
+}
+
+fn to_rgba(
+    data: &[u8],
+    size: UVec2,
+    color_depth: u16,
+    palette: &[[u8; 4]],
+    transparent_index: Option<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let pixel_count = (size.x * size.y) as usize;
+    let bytes_per_pixel = color_depth as usize / 8;
+    ensure!(
+        data.len() >= pixel_count * bytes_per_pixel,
+        "cel has {} bytes of pixels, expected {}",
+        data.len(),
+        pixel_count * bytes_per_pixel
+    );
+    let pixels = &data[..pixel_count * bytes_per_pixel];
+    Ok(match color_depth {
+        32 => pixels.to_vec(),
+        16 => pixels
+            .chunks_exact(2)
+            .flat_map(|pixel| [pixel[0], pixel[0], pixel[0], pixel[1]])
+            .collect(),
+        _ => pixels
+            .iter()
+            .flat_map(|index| {
+                if Some(*index) == transparent_index {
+                    [0; 4]
+                } else {
+                    palette[*index as usize]
+                }
+            })
+            .collect(),
+    })
+}
+
+fn read_palette(chunk: &mut Reader, palette: &mut Vec<[u8; 4]>) -> anyhow::Result<()> {
+    let size = chunk.dword()? as usize;
+    let first = chunk.dword()? as usize;
+    let last = chunk.dword()? as usize;
+    chunk.skip(8)?;
+    if palette.len() < size {
+        palette.resize(size, [0; 4]);
+    }
+    for index in first..=last {
+        let flags = chunk.word()?;
+        let color = [chunk.byte()?, chunk.byte()?, chunk.byte()?, chunk.byte()?];
+        if flags & 1 != 0 {
+            chunk.string()?;
+        }
+        if let Some(entry) = palette.get_mut(index) {
+            *entry = color;
+        }
+    }
+    Ok(())
+}
+
+fn read_old_palette(chunk: &mut Reader, palette: &mut [[u8; 4]]) -> anyhow::Result<()> {
+    let packets = chunk.word()?;
+    let mut index = 0usize;
+    for _ in 0..packets {
+        index += chunk.byte()? as usize;
+        let count = match chunk.byte()? {
+            0 => 256,
+            count => count as usize,
+        };
+        for _ in 0..count {
+            let color = [chunk.byte()?, chunk.byte()?, chunk.byte()?, 255];
+            if let Some(entry) = palette.get_mut(index) {
+                *entry = color;
+            }
+            index += 1;
+        }
+    }
+    Ok(())
+}
+
+// little-endian reader over a chunk of the file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> anyhow::Result<&'a [u8]> {
+        if self.position + count > self.bytes.len() {
+            bail!("unexpected end of file");
+        }
+        let bytes = &self.bytes[self.position..self.position + count];
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let bytes = &self.bytes[self.position.min(self.bytes.len())..];
+        self.position = self.bytes.len();
+        bytes
+    }
+
+    fn skip(&mut self, count: usize) -> anyhow::Result<()> {
+        self.take(count).map(|_| ())
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> anyhow::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn short(&mut self) -> anyhow::Result<i16> {
+        Ok(self.word()? as i16)
+    }
+
+    fn dword(&mut self) -> anyhow::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn long(&mut self) -> anyhow::Result<i32> {
+        Ok(self.dword()? as i32)
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let length = self.word()? as usize;
+        Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(path: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/assets/{}", env!("CARGO_MANIFEST_DIR"), path)).unwrap()
+    }
+
+    fn parse(path: &str) -> AseFile {
+        AseFile::parse(&read(path)).unwrap()
+    }
+
+    #[test]
+    fn frames_and_tags_are_read() {
+        let file = parse("MaplestoryDefaultSpriteSheet/maple-default.ase");
+        assert_eq!((file.width, file.height), (67, 78));
+        assert_eq!(file.frames.len(), 121);
+        assert!(file.frames.iter().all(|frame| frame.duration == 100));
+
+        let tag = |name: &str| {
+            let tag = file.tags.iter().find(|tag| tag.name == name).unwrap();
+            (tag.from, tag.to, tag.direction)
+        };
+        assert_eq!(tag("Alert"), (0, 3, TagDirection::Forward));
+        assert_eq!(tag("Attack"), (21, 26, TagDirection::Forward));
+        assert_eq!(tag("Sit"), (31, 32, TagDirection::Forward));
+        assert_eq!(tag("Stand-Idle"), (56, 59, TagDirection::Forward));
+        assert_eq!(tag("Walking-1"), (111, 115, TagDirection::Forward));
+    }
+
+    #[test]
+    fn frames_are_composited_like_the_export() {
+        let file = parse("MaplestoryDefaultSpriteSheet/maple-default.ase");
+        // exported by Aseprite as a sheet of 10 columns
+        let sheet =
+            image::load_from_memory(&read("MaplestoryDefaultSpriteSheet/maple-default.png"))
+                .unwrap()
+                .to_rgba8();
+        for frame in [0, 57] {
+            let pixels = file.composite(frame);
+            let (left, top) = ((frame % 10) as u32 * 67, (frame / 10) as u32 * 78);
+            for y in 0..78 {
+                for x in 0..67 {
+                    let index = ((y * 67 + x) * 4) as usize;
+                    assert_eq!(
+                        pixels[index..index + 4],
+                        sheet.get_pixel(left + x, top + y).0,
+                        "frame {} at ({}, {})",
+                        frame,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+        // the outline at the top of the head
+        let pixels = file.composite(0);
+        let index = (17 * 67 + 26) * 4;
+        assert_eq!(pixels[index..index + 4], [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn layers_are_read() {
+        let file = parse("parallax-cube/Parallax-Cube.ase");
+        assert_eq!((file.width, file.height), (128, 128));
+        assert_eq!(file.frames.len(), 1);
+        let names: Vec<&str> = file
+            .layers
+            .iter()
+            .map(|layer| layer.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            ["Layer 1", "Layer 6", "Layer 5", "Layer 4", "Layer 3", "Layer 2"]
+        );
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let bytes = read("MaplestoryDefaultSpriteSheet/maple-default.ase");
+        // in the header, in the first frame's header, and in one of its chunks
+        for length in [64, 130, 300, bytes.len() / 2] {
+            assert!(
+                AseFile::parse(&bytes[..length]).is_err(),
+                "{} bytes parsed",
+                length
+            );
+        }
+        assert!(AseFile::parse(&read("MaplestoryDefaultSpriteSheet/maple-default.png")).is_err());
+    }
+}
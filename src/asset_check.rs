@@ -0,0 +1,261 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use bevy_parallax::ParallaxResource;
+
+use crate::atlas::SHEET_EXTENSION;
+use crate::prefab::Prefab;
+
+/* Checks every asset path the game references against the files on disk before
+anything is spawned. Paths are compared case-sensitively even on filesystems that
+are not, so `Parallax-Cube-1.png` referenced as `parallax-cube-1.png` is caught on
+Windows/macOS instead of silently failing on Linux.
+ */
+pub struct AssetCheckPlugin;
+
+impl Plugin for AssetCheckPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AssetManifest>()
+            .add_state(LoadingState::Checking)
+            .add_system_set(
+                SystemSet::on_enter(LoadingState::Checking).with_system(check_asset_manifest),
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LoadingState {
+    Checking,
+    Ready,
+    Failed,
+}
+
+/// Asset paths (relative to the asset folder) registered by plugins, with where they come from.
+#[derive(Default)]
+pub struct AssetManifest {
+    pub entries: Vec<(String, String)>,
+}
+
+impl AssetManifest {
+    pub fn add(&mut self, source: &str, path: &str) {
+        self.entries.push((source.to_string(), path.to_string()));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AssetProblem {
+    Missing {
+        source: String,
+        path: String,
+        suggestion: Option<String>,
+    },
+    CaseMismatch {
+        source: String,
+        path: String,
+        actual: String,
+    },
+    Invalid {
+        source: String,
+        path: String,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for AssetProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AssetProblem::Missing {
+                source,
+                path,
+                suggestion: Some(suggestion),
+            } => write!(
+                f,
+                "{}: \"{}\" not found, did you mean \"{}\"?",
+                source, path, suggestion
+            ),
+            AssetProblem::Missing { source, path, .. } => {
+                write!(f, "{}: \"{}\" not found", source, path)
+            }
+            AssetProblem::CaseMismatch {
+                source,
+                path,
+                actual,
+            } => write!(
+                f,
+                "{}: \"{}\" only matches \"{}\" ignoring case",
+                source, path, actual
+            ),
+            AssetProblem::Invalid {
+                source,
+                path,
+                reason,
+            } => write!(f, "{}: \"{}\" could not be read: {}", source, path, reason),
+        }
+    }
+}
+
+/// The problems found by the last check; empty when every path resolved.
+#[derive(Default)]
+pub struct AssetReport {
+    pub problems: Vec<AssetProblem>,
+}
+
+// same lookup as bevy's `FileAssetIo`
+pub fn asset_root() -> PathBuf {
+    let base = std::env::var("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            std::env::current_exe()
+                .ok()
+                .and_then(|exe| exe.parent().map(Path::to_path_buf))
+                .unwrap_or_default()
+        });
+    base.join("assets")
+}
+
+fn check_asset_manifest(
+    mut commands: Commands,
+    mut manifest: ResMut<AssetManifest>,
+    parallax: Option<Res<ParallaxResource>>,
+    mut state: ResMut<State<LoadingState>>,
+) {
+    let root = asset_root();
+    let mut problems = Vec::new();
+
+    if let Some(parallax) = parallax {
+        for layer in parallax.layer_data.iter() {
+            manifest.add("parallax layer", &layer.path);
+        }
+    }
+    add_prefab_paths(&root, &mut manifest, &mut problems);
+
+    for (source, path) in manifest.entries.iter() {
+        if let Err(problem) = resolve_asset_path(&root, source, path) {
+            problems.push(problem);
+        }
+    }
+
+    if problems.is_empty() {
+        info!("asset check: {} paths ok", manifest.entries.len());
+        state.set(LoadingState::Ready).unwrap();
+    } else {
+        let mut report = format!("asset check failed with {} problem(s):", problems.len());
+        for problem in problems.iter() {
+            report.push_str(&format!("\n  - {}", problem));
+        }
+        error!("{}", report);
+        state.set(LoadingState::Failed).unwrap();
+    }
+    commands.insert_resource(AssetReport { problems });
+}
+
+fn add_prefab_paths(root: &Path, manifest: &mut AssetManifest, problems: &mut Vec<AssetProblem>) {
+    let entries = match fs::read_dir(root.join("prefabs")) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let prefab_path = format!("prefabs/{}", name);
+        if !name.ends_with(".prefab.ron") {
+            continue;
+        }
+        let parsed = fs::read(entry.path())
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| ron::de::from_bytes::<Prefab>(&bytes).map_err(|err| err.to_string()));
+        match parsed {
+            Ok(prefab) => {
+                for path in prefab.asset_paths() {
+                    manifest.add(&prefab_path, path);
+                }
+                // anything else would not be loaded as sheet metadata
+                if let Some(metadata) = prefab
+                    .metadata
+                    .filter(|metadata| !metadata.ends_with(SHEET_EXTENSION))
+                {
+                    problems.push(AssetProblem::Invalid {
+                        source: prefab_path.clone(),
+                        path: metadata,
+                        reason: format!("sheet metadata must end in .{}", SHEET_EXTENSION),
+                    });
+                }
+            }
+            Err(reason) => problems.push(AssetProblem::Invalid {
+                source: "prefab".to_string(),
+                path: prefab_path,
+                reason,
+            }),
+        }
+    }
+}
+
+/// Walks `path` one component at a time, comparing names exactly.
+pub fn resolve_asset_path(root: &Path, source: &str, path: &str) -> Result<(), AssetProblem> {
+    let mut current = root.to_path_buf();
+    let mut actual = Vec::new();
+    let mut case_mismatch = false;
+
+    for part in path.split('/').filter(|part| !part.is_empty()) {
+        let names: Vec<String> = fs::read_dir(&current)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|entry| entry.file_name().to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let found = if names.iter().any(|name| name == part) {
+            part.to_string()
+        } else if let Some(name) = names.iter().find(|name| name.eq_ignore_ascii_case(part)) {
+            case_mismatch = true;
+            name.clone()
+        } else {
+            let suggestion = closest_name(part, &names).map(|name| {
+                actual.push(name);
+                actual.join("/")
+            });
+            return Err(AssetProblem::Missing {
+                source: source.to_string(),
+                path: path.to_string(),
+                suggestion,
+            });
+        };
+        current.push(&found);
+        actual.push(found);
+    }
+
+    if case_mismatch {
+        return Err(AssetProblem::CaseMismatch {
+            source: source.to_string(),
+            path: path.to_string(),
+            actual: actual.join("/"),
+        });
+    }
+    Ok(())
+}
+
+fn closest_name(part: &str, names: &[String]) -> Option<String> {
+    let part = part.to_lowercase();
+    names
+        .iter()
+        .map(|name| (edit_distance(&part, &name.to_lowercase()), name))
+        .filter(|(distance, _)| *distance <= 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name.clone())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,511 @@
+use std::fmt;
+
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+pub struct AtlasPlugin;
+
+impl Plugin for AtlasPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<SpriteSheetMeta>()
+            .init_asset_loader::<SpriteSheetMetaLoader>();
+    }
+}
+
+/// Extension of sheet metadata files, so other JSON assets are left to their own loaders.
+pub const SHEET_EXTENSION: &str = "sheet.json";
+
+/* Sprite sheet metadata as exported by Aseprite ("Export Sprite Sheet" > JSON data),
+with frames either as a hash or an array, saved as `<name>.sheet.json`. Only the
+fields the game uses are read.
+ */
+#[derive(Deserialize, TypeUuid)]
+#[uuid = "9d3c1b7e-8a2f-4c6d-b5e4-1f0a7c3d2e91"]
+pub struct SpriteSheetMeta {
+    #[serde(deserialize_with = "deserialize_frames")]
+    pub frames: Vec<SheetFrame>,
+    pub meta: SheetInfo,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct SheetRect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct SheetSize {
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SheetFrame {
+    pub frame: SheetRect,
+    #[serde(default)]
+    pub trimmed: bool,
+    pub sprite_source_size: SheetRect,
+    pub source_size: SheetSize,
+    // milliseconds
+    pub duration: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SheetInfo {
+    pub image: String,
+    pub size: SheetSize,
+    #[serde(default)]
+    pub frame_tags: Vec<SheetTag>,
+    #[serde(default)]
+    pub slices: Vec<SheetSlice>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SheetTag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    // "forward", "reverse", "pingpong" or "pingpong_reverse"
+    #[serde(default = "default_direction")]
+    pub direction: String,
+}
+
+fn default_direction() -> String {
+    "forward".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SheetSlice {
+    pub name: String,
+    pub keys: Vec<SheetSliceKey>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SheetSliceKey {
+    pub frame: usize,
+    pub bounds: SheetRect,
+    // both relative to `bounds`
+    #[serde(default)]
+    pub center: Option<SheetRect>,
+    #[serde(default)]
+    pub pivot: Option<SheetPoint>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct SheetPoint {
+    pub x: i32,
+    pub y: i32,
+}
+
+// keeps the order of hash-style frames, which a map type would sort by name
+fn deserialize_frames<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SheetFrame>, D::Error> {
+    struct FramesVisitor;
+
+    impl<'de> Visitor<'de> for FramesVisitor {
+        type Value = Vec<SheetFrame>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a map or list of frames")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut frames = Vec::new();
+            while let Some((_, frame)) = map.next_entry::<String, SheetFrame>()? {
+                frames.push(frame);
+            }
+            Ok(frames)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut frames = Vec::new();
+            while let Some(frame) = seq.next_element::<SheetFrame>()? {
+                frames.push(frame);
+            }
+            Ok(frames)
+        }
+    }
+
+    deserializer.deserialize_any(FramesVisitor)
+}
+
+#[derive(Default)]
+pub struct SpriteSheetMetaLoader;
+
+impl AssetLoader for SpriteSheetMetaLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let meta: SpriteSheetMeta = serde_json::from_slice(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(meta));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[SHEET_EXTENSION]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AtlasGrid {
+    pub cell_size: Vec2,
+    pub columns: usize,
+    pub rows: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AtlasError {
+    GridLargerThanImage {
+        grid: UVec2,
+        image: UVec2,
+    },
+    ImageNotDivisible {
+        cell_size: UVec2,
+        image: UVec2,
+    },
+    MetadataImageSize {
+        metadata: UVec2,
+        image: UVec2,
+    },
+    TooManyFrames {
+        metadata_frames: usize,
+        grid_cells: usize,
+    },
+    FrameOffGrid {
+        index: usize,
+        expected: UVec2,
+        found: UVec2,
+    },
+    FrameOutsideImage {
+        index: usize,
+        image: UVec2,
+    },
+    AnimationOutOfRange {
+        animation: String,
+        first: usize,
+        last: usize,
+        frames: usize,
+    },
+}
+
+impl fmt::Display for AtlasError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtlasError::GridLargerThanImage { grid, image } => write!(
+                f,
+                "grid covers {}x{}px but the image is only {}x{}px",
+                grid.x, grid.y, image.x, image.y
+            ),
+            AtlasError::ImageNotDivisible { cell_size, image } => write!(
+                f,
+                "{}x{}px image is not a whole number of {}x{}px cells",
+                image.x, image.y, cell_size.x, cell_size.y
+            ),
+            AtlasError::MetadataImageSize { metadata, image } => write!(
+                f,
+                "metadata expects a {}x{}px image but it is {}x{}px",
+                metadata.x, metadata.y, image.x, image.y
+            ),
+            AtlasError::TooManyFrames {
+                metadata_frames,
+                grid_cells,
+            } => write!(
+                f,
+                "metadata has {} frames but the grid only has {} cells",
+                metadata_frames, grid_cells
+            ),
+            AtlasError::FrameOffGrid {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "frame {} is at ({}, {}) instead of the grid cell at ({}, {})",
+                index, found.x, found.y, expected.x, expected.y
+            ),
+            AtlasError::FrameOutsideImage { index, image } => write!(
+                f,
+                "frame {} lies outside the {}x{}px image",
+                index, image.x, image.y
+            ),
+            AtlasError::AnimationOutOfRange {
+                animation,
+                first,
+                last,
+                frames,
+            } => write!(
+                f,
+                "animation \"{}\" uses frames {}..={} but the atlas has {} frames",
+                animation, first, last, frames
+            ),
+        }
+    }
+}
+
+/// Checks a grid against the loaded image and, if present, the exported frame metadata.
+/// Returns how many frames of the grid are usable: the metadata frame count when there is
+/// metadata (the last row is often only partly filled), every cell otherwise.
+pub fn validate_grid(
+    grid: &AtlasGrid,
+    image_size: Vec2,
+    meta: Option<&SpriteSheetMeta>,
+) -> Result<usize, Vec<AtlasError>> {
+    let mut errors = Vec::new();
+    let cell_size = grid.cell_size.as_uvec2();
+    let image = image_size.as_uvec2();
+    let grid_size = UVec2::new(
+        cell_size.x * grid.columns as u32,
+        cell_size.y * grid.rows as u32,
+    );
+    let grid_cells = grid.columns * grid.rows;
+
+    if grid_size.x > image.x || grid_size.y > image.y {
+        errors.push(AtlasError::GridLargerThanImage {
+            grid: grid_size,
+            image,
+        });
+    } else if cell_size.x == 0
+        || cell_size.y == 0
+        || image.x % cell_size.x != 0
+        || image.y % cell_size.y != 0
+    {
+        errors.push(AtlasError::ImageNotDivisible { cell_size, image });
+    }
+
+    let mut frames = grid_cells;
+    if let Some(meta) = meta {
+        let metadata = UVec2::new(meta.meta.size.w, meta.meta.size.h);
+        if metadata != image {
+            errors.push(AtlasError::MetadataImageSize { metadata, image });
+        }
+        if meta.frames.len() > grid_cells {
+            errors.push(AtlasError::TooManyFrames {
+                metadata_frames: meta.frames.len(),
+                grid_cells,
+            });
+        }
+        for (index, frame) in meta.frames.iter().enumerate().take(grid_cells) {
+            let expected = UVec2::new(
+                (index % grid.columns) as u32 * cell_size.x,
+                (index / grid.columns) as u32 * cell_size.y,
+            );
+            let found = UVec2::new(frame.frame.x, frame.frame.y);
+            if expected != found {
+                errors.push(AtlasError::FrameOffGrid {
+                    index,
+                    expected,
+                    found,
+                });
+            }
+        }
+        frames = meta.frames.len().min(grid_cells);
+    }
+
+    if errors.is_empty() {
+        Ok(frames)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Checks trimmed sheets, whose frames are packed at their own size instead of on a grid.
+pub fn validate_sheet(image_size: Vec2, meta: &SpriteSheetMeta) -> Result<usize, Vec<AtlasError>> {
+    let mut errors = Vec::new();
+    let image = image_size.as_uvec2();
+    let metadata = UVec2::new(meta.meta.size.w, meta.meta.size.h);
+    if metadata != image {
+        errors.push(AtlasError::MetadataImageSize { metadata, image });
+    }
+    for (index, frame) in meta.frames.iter().enumerate() {
+        let rect = frame.frame;
+        if rect.x + rect.w > image.x || rect.y + rect.h > image.y {
+            errors.push(AtlasError::FrameOutsideImage { index, image });
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(meta.frames.len())
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn validate_animation(
+    animation: &str,
+    first: usize,
+    last: usize,
+    frames: usize,
+) -> Result<(), AtlasError> {
+    if first > last || last >= frames {
+        return Err(AtlasError::AnimationOutOfRange {
+            animation: animation.to_string(),
+            first,
+            last,
+            frames,
+        });
+    }
+    Ok(())
+}
+
+/// Builds the atlas for a validated grid, dropping the cells past the last real frame.
+pub fn grid_atlas(texture: Handle<Image>, grid: &AtlasGrid, frames: usize) -> TextureAtlas {
+    let mut atlas = TextureAtlas::from_grid(texture, grid.cell_size, grid.columns, grid.rows);
+    atlas.textures.truncate(frames);
+    atlas
+}
+
+/// Builds the atlas from the metadata frame rects, for sheets with trimmed frames.
+pub fn sheet_atlas(
+    texture: Handle<Image>,
+    image_size: Vec2,
+    meta: &SpriteSheetMeta,
+) -> TextureAtlas {
+    let mut atlas = TextureAtlas::new_empty(texture, image_size);
+    for frame in meta.frames.iter() {
+        let min = Vec2::new(frame.frame.x as f32, frame.frame.y as f32);
+        atlas.add_texture(bevy::sprite::Rect {
+            min,
+            max: min + Vec2::new(frame.frame.w as f32, frame.frame.h as f32),
+        });
+    }
+    atlas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a sheet of 16x16 frames at `positions`, for a `size` image
+    fn meta(size: (u32, u32), positions: &[(u32, u32)]) -> SpriteSheetMeta {
+        let frames: Vec<serde_json::Value> = positions
+            .iter()
+            .map(|(x, y)| {
+                serde_json::json!({
+                    "frame": {"x": x, "y": y, "w": 16, "h": 16},
+                    "spriteSourceSize": {"x": 0, "y": 0, "w": 16, "h": 16},
+                    "sourceSize": {"w": 16, "h": 16},
+                    "duration": 100
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "frames": frames,
+            "meta": {"image": "sheet.png", "size": {"w": size.0, "h": size.1}}
+        }))
+        .unwrap()
+    }
+
+    fn grid(columns: usize, rows: usize) -> AtlasGrid {
+        AtlasGrid {
+            cell_size: Vec2::new(16.0, 16.0),
+            columns,
+            rows,
+        }
+    }
+
+    #[test]
+    fn grids_must_fit_and_divide_the_image() {
+        let image = Vec2::new(64.0, 32.0);
+        assert_eq!(validate_grid(&grid(4, 2), image, None), Ok(8));
+        assert_eq!(
+            validate_grid(&grid(5, 2), image, None),
+            Err(vec![AtlasError::GridLargerThanImage {
+                grid: UVec2::new(80, 32),
+                image: UVec2::new(64, 32),
+            }])
+        );
+        assert_eq!(
+            validate_grid(&grid(3, 2), Vec2::new(60.0, 32.0), None),
+            Err(vec![AtlasError::ImageNotDivisible {
+                cell_size: UVec2::new(16, 16),
+                image: UVec2::new(60, 32),
+            }])
+        );
+    }
+
+    #[test]
+    fn grids_must_agree_with_their_metadata() {
+        let image = Vec2::new(32.0, 32.0);
+        // the last row is only partly filled
+        let partial = meta((32, 32), &[(0, 0), (16, 0), (0, 16)]);
+        assert_eq!(validate_grid(&grid(2, 2), image, Some(&partial)), Ok(3));
+
+        let resized = meta((64, 32), &[(0, 0)]);
+        assert_eq!(
+            validate_grid(&grid(2, 2), image, Some(&resized)),
+            Err(vec![AtlasError::MetadataImageSize {
+                metadata: UVec2::new(64, 32),
+                image: UVec2::new(32, 32),
+            }])
+        );
+
+        let too_many = meta((32, 32), &[(0, 0), (16, 0), (0, 16), (16, 16), (0, 0)]);
+        assert_eq!(
+            validate_grid(&grid(2, 2), image, Some(&too_many)),
+            Err(vec![AtlasError::TooManyFrames {
+                metadata_frames: 5,
+                grid_cells: 4,
+            }])
+        );
+
+        let shifted = meta((32, 32), &[(0, 0), (0, 16)]);
+        assert_eq!(
+            validate_grid(&grid(2, 2), image, Some(&shifted)),
+            Err(vec![AtlasError::FrameOffGrid {
+                index: 1,
+                expected: UVec2::new(16, 0),
+                found: UVec2::new(0, 16),
+            }])
+        );
+    }
+
+    #[test]
+    fn sheet_frames_must_lie_inside_the_image() {
+        let image = Vec2::new(32.0, 32.0);
+        // packed frames need not be on a grid
+        let packed = meta((32, 32), &[(0, 0), (10, 16)]);
+        assert_eq!(validate_sheet(image, &packed), Ok(2));
+
+        let outside = meta((32, 32), &[(0, 0), (20, 0)]);
+        assert_eq!(
+            validate_sheet(image, &outside),
+            Err(vec![AtlasError::FrameOutsideImage {
+                index: 1,
+                image: UVec2::new(32, 32),
+            }])
+        );
+        assert_eq!(
+            validate_sheet(Vec2::new(48.0, 32.0), &packed),
+            Err(vec![AtlasError::MetadataImageSize {
+                metadata: UVec2::new(32, 32),
+                image: UVec2::new(48, 32),
+            }])
+        );
+    }
+
+    #[test]
+    fn animations_must_be_ranges_inside_the_atlas() {
+        assert_eq!(validate_animation("walk", 0, 3, 4), Ok(()));
+        assert_eq!(validate_animation("idle", 2, 2, 4), Ok(()));
+        let out_of_range = |first, last| AtlasError::AnimationOutOfRange {
+            animation: "walk".to_string(),
+            first,
+            last,
+            frames: 4,
+        };
+        assert_eq!(validate_animation("walk", 1, 4, 4), Err(out_of_range(1, 4)));
+        assert_eq!(validate_animation("walk", 3, 1, 4), Err(out_of_range(3, 1)));
+    }
+}
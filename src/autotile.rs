@@ -0,0 +1,334 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+use crate::collision::CollisionGrid;
+use crate::ldtk::{AutoRule, Checker, LayerDef, LdtkProject, TileMode, TilesetDef};
+use crate::tilemap::{rebuild_dirty_chunks, Tile, TileLayer};
+
+/// Pattern value matching any non-empty cell; negated, it matches empty cells.
+pub const AUTO_LAYER_ANYTHING: i32 = 1000001;
+
+pub struct AutoTilePlugin;
+
+impl Plugin for AutoTilePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            update_auto_layers.before(rebuild_dirty_chunks),
+        );
+    }
+}
+
+/* Rule-painted tiles of a layer, re-evaluated from the IntGrid values whenever they
+change. The game has a single IntGrid, `CollisionGrid`, covering all loaded levels;
+it is the rules' source both for IntGrid layers with rules and for auto-layers
+reading another layer. Like in LDtk, cells outside the layer's level count as out of
+bounds.
+ */
+#[derive(Component)]
+pub struct AutoLayer {
+    pub project: Handle<LdtkProject>,
+    pub layer_def_uid: i32,
+    pub tileset_def_uid: i32,
+    pub seed: i64,
+    // optional rule groups enabled on this layer instance
+    pub optional_rules: Vec<i32>,
+    // world position of the level's top-left corner
+    pub origin: Vec2,
+    // evaluated at least once, e.g. for layers exported without tiles
+    pub evaluated: bool,
+}
+
+fn update_auto_layers(
+    grid: Res<CollisionGrid>,
+    projects: Res<Assets<LdtkProject>>,
+    mut layers: Query<(&mut AutoLayer, &mut TileLayer)>,
+) {
+    for (mut auto_layer, mut layer) in layers.iter_mut() {
+        if auto_layer.evaluated && !grid.is_changed() {
+            continue;
+        }
+        let project = match projects.get(&auto_layer.project) {
+            Some(project) => project,
+            None => continue,
+        };
+        let def = match project.layer_def(auto_layer.layer_def_uid) {
+            Some(def) => def,
+            None => continue,
+        };
+        let tileset = match project.tileset(auto_layer.tileset_def_uid) {
+            Some(tileset) => tileset,
+            None => continue,
+        };
+
+        let (width, height) = (layer.width, layer.height);
+        let (offset_x, offset_y) = grid.cell_at(auto_layer.origin + Vec2::new(0.5, -0.5));
+        let cells = evaluate_rules(
+            def,
+            tileset,
+            &auto_layer.optional_rules,
+            auto_layer.seed,
+            width,
+            height,
+            |x, y| {
+                if x < 0 || y < 0 || x >= width || y >= height {
+                    None
+                } else {
+                    Some(grid.value(x + offset_x, y + offset_y))
+                }
+            },
+        );
+        for (index, tiles) in cells.into_iter().enumerate() {
+            let index = index as i32;
+            layer.set_tiles(index % width, index / width, tiles);
+        }
+        auto_layer.evaluated = true;
+    }
+}
+
+/// Runs the layer's rules over a `width` by `height` grid, like LDtk does in the editor.
+/// `value` gives the IntGrid value of a cell, or None outside the level. Returns the tiles
+/// of every cell, row by row from the top-left one, bottom tile first.
+///
+/// Perlin noise is not supported; rules using it are skipped.
+pub fn evaluate_rules(
+    def: &LayerDef,
+    tileset: &TilesetDef,
+    optional_rules: &[i32],
+    seed: i64,
+    width: i32,
+    height: i32,
+    value: impl Fn(i32, i32) -> Option<i32>,
+) -> Vec<Vec<Tile>> {
+    let mut cells: Vec<Vec<Tile>> = vec![Vec::new(); (width * height).max(0) as usize];
+    // cells matched by a break-on-match rule, which later rules leave alone
+    let mut done: HashSet<(i32, i32)> = HashSet::default();
+
+    let groups = def.auto_rule_groups.iter().filter(|group| {
+        group.active && (!group.is_optional || optional_rules.contains(&group.uid))
+    });
+    for group in groups {
+        for rule in group.rules.iter() {
+            if !rule.active || rule.perlin_active || rule.tile_ids.is_empty() {
+                continue;
+            }
+            for y in 0..height {
+                for x in 0..width {
+                    if done.contains(&(x, y)) || !modulo_matches(rule, x, y) {
+                        continue;
+                    }
+                    let mut matched = false;
+                    // unflipped first, then the enabled mirrors; only the first match is
+                    // placed
+                    for flips in 0..4u8 {
+                        let (flip_x, flip_y) = (flips & 1 != 0, flips & 2 != 0);
+                        if (flip_x && !rule.flip_x) || (flip_y && !rule.flip_y) {
+                            continue;
+                        }
+                        let direction =
+                            IVec2::new(if flip_x { -1 } else { 1 }, if flip_y { -1 } else { 1 });
+                        if rule_matches(rule, seed, x, y, direction, &value) {
+                            place_tiles(
+                                rule, def, tileset, seed, x, y, flips, width, height, &mut cells,
+                            );
+                            matched = true;
+                            break;
+                        }
+                    }
+                    if matched && rule.break_on_match {
+                        done.insert((x, y));
+                    }
+                }
+            }
+        }
+    }
+
+    // the first rule is drawn on top
+    for tiles in cells.iter_mut() {
+        tiles.reverse();
+    }
+    cells
+}
+
+fn modulo_matches(rule: &AutoRule, x: i32, y: i32) -> bool {
+    let (x_modulo, y_modulo) = (rule.x_modulo.max(1), rule.y_modulo.max(1));
+    let y_ok = match rule.checker {
+        Checker::Vertical => (y + (x / x_modulo) % 2) % y_modulo == 0,
+        _ => (y - rule.y_offset) % y_modulo == 0,
+    };
+    let x_ok = match rule.checker {
+        Checker::Horizontal => (x + (y / y_modulo) % 2) % x_modulo == 0,
+        _ => (x - rule.x_offset) % x_modulo == 0,
+    };
+    x_ok && y_ok
+}
+
+fn rule_matches(
+    rule: &AutoRule,
+    seed: i64,
+    x: i32,
+    y: i32,
+    direction: IVec2,
+    value: &impl Fn(i32, i32) -> Option<i32>,
+) -> bool {
+    if rule.chance <= 0.0
+        || (rule.chance < 1.0
+            && rand_seed_coords(seed + rule.uid as i64, x, y, 100) as f32 >= rule.chance * 100.0)
+    {
+        return false;
+    }
+
+    let radius = rule.size / 2;
+    for py in 0..rule.size {
+        for px in 0..rule.size {
+            let expected = match rule.pattern.get((px + py * rule.size) as usize) {
+                Some(0) | None => continue,
+                Some(expected) => *expected,
+            };
+            let cell_x = x + direction.x * (px - radius);
+            let cell_y = y + direction.y * (py - radius);
+            let actual = match value(cell_x, cell_y).or(rule.out_of_bounds_value) {
+                Some(actual) => actual,
+                None => return false,
+            };
+            let matches = if expected.abs() == AUTO_LAYER_ANYTHING {
+                actual != 0
+            } else {
+                actual == expected.abs()
+            };
+            // negative values are "anything but"
+            if matches != (expected > 0) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[allow(clippy::too_many_arguments)]
+fn place_tiles(
+    rule: &AutoRule,
+    def: &LayerDef,
+    tileset: &TilesetDef,
+    seed: i64,
+    x: i32,
+    y: i32,
+    flips: u8,
+    width: i32,
+    height: i32,
+    cells: &mut [Vec<Tile>],
+) {
+    let tile = |id: i32| Tile {
+        id,
+        flip_x: flips & 1 != 0,
+        flip_y: flips & 2 != 0,
+    };
+    match rule.tile_mode {
+        TileMode::Single => {
+            let pick = rand_seed_coords(seed + rule.uid as i64, x, y, rule.tile_ids.len() as i32);
+            cells[(y * width + x) as usize].push(tile(rule.tile_ids[pick as usize]));
+        }
+        TileMode::Stamp => {
+            // the tiles keep their layout in the tileset, around the rule's pivot
+            let columns = tileset.columns();
+            let position = |id: i32| IVec2::new(id % columns, id / columns);
+            let min = rule
+                .tile_ids
+                .iter()
+                .map(|id| position(*id))
+                .fold(IVec2::splat(i32::MAX), IVec2::min);
+            let max = rule
+                .tile_ids
+                .iter()
+                .map(|id| position(*id))
+                .fold(IVec2::splat(i32::MIN), IVec2::max);
+            let grid_size = def.grid_size as f32;
+            for id in rule.tile_ids.iter() {
+                let from_min = position(*id) - min;
+                // LDtk offsets in pixels; half-cell pivots snap to the cell grid
+                let mut offset = Vec2::new(
+                    ((from_min.x as f32 - rule.pivot_x * (max.x - min.x) as f32) * grid_size)
+                        .trunc(),
+                    ((from_min.y as f32 - rule.pivot_y * (max.y - min.y) as f32) * grid_size)
+                        .trunc(),
+                );
+                if flips & 1 != 0 {
+                    offset.x = -offset.x;
+                }
+                if flips & 2 != 0 {
+                    offset.y = -offset.y;
+                }
+                let cell_x = x + (offset.x / grid_size).floor() as i32;
+                let cell_y = y + (offset.y / grid_size).floor() as i32;
+                if cell_x >= 0 && cell_y >= 0 && cell_x < width && cell_y < height {
+                    cells[(cell_y * width + cell_x) as usize].push(tile(*id));
+                }
+            }
+        }
+    }
+}
+
+/* LDtk's coordinate hash (deepnightLibs' `M.randSeedCoords`). The editor runs it as
+JavaScript, where the middle product is a double; it's reproduced here so chance and
+random tile picks land on the same cells as in the editor.
+ */
+pub fn rand_seed_coords(seed: i64, x: i32, y: i32, max: i32) -> i32 {
+    let to_int32 = |value: i64| value as i32;
+    let h = to_int32(seed + x as i64 * 374761393 + y as i64 * 668265263);
+    let h = h ^ (h >> 13);
+    let h = to_int32((h as f64 * 1274126177.0) as i64);
+    let h = h ^ (h >> 16);
+    (h % max.max(1)).abs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_reproduce_the_exported_tiles() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/Levels/basic.ldtk");
+        let project: LdtkProject = serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap();
+        let level = &project.levels[0];
+        let layer = level.int_grid().unwrap();
+        let def = project.layer_def(layer.layer_def_uid).unwrap();
+        let tileset = project.tileset(layer.tileset_def_uid.unwrap()).unwrap();
+        let (width, height) = (layer.c_wid, layer.c_hei);
+
+        let cells = evaluate_rules(
+            def,
+            tileset,
+            &layer.optional_rules,
+            layer.seed,
+            width,
+            height,
+            |x, y| {
+                if x < 0 || y < 0 || x >= width || y >= height {
+                    None
+                } else {
+                    Some(layer.int_grid_csv[(y * width + x) as usize])
+                }
+            },
+        );
+        let mut evaluated: Vec<(i32, i32, i32, u8)> = Vec::new();
+        for (index, tiles) in cells.iter().enumerate() {
+            let (x, y) = (index as i32 % width, index as i32 / width);
+            for tile in tiles {
+                let flips = tile.flip_x as u8 | (tile.flip_y as u8) << 1;
+                evaluated.push((x, y, tile.id, flips));
+            }
+        }
+        let mut exported: Vec<(i32, i32, i32, u8)> = layer
+            .auto_layer_tiles
+            .iter()
+            .map(|tile| {
+                let size = layer.grid_size;
+                (tile.px[0] / size, tile.px[1] / size, tile.t, tile.f)
+            })
+            .collect();
+        evaluated.sort_unstable();
+        exported.sort_unstable();
+        assert_eq!(exported.len(), 308);
+        assert_eq!(evaluated, exported);
+    }
+}
@@ -0,0 +1,483 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{GenericImage, GenericImageView, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+// reuses the sheet metadata format the game loads
+#[allow(dead_code)]
+#[path = "../atlas.rs"]
+mod atlas;
+
+use atlas::SpriteSheetMeta;
+
+/* Packs sprite images and Aseprite sheet exports into one atlas image plus Aseprite-style
+JSON metadata, which the game loads like any exported sheet (see `atlas.rs`).
+
+    cargo run --bin pack_atlas -- assets/atlases/characters.atlas.ron
+
+Paths in the manifest are relative to the asset folder. Sources are either a path, or
+`(path: ..., cell_size: (w, h), columns: ..., rows: ...)` for a plain image laid out as a
+grid. Frames of each source stay
+contiguous and in order; tags and slices are shifted to the frame's new index.
+ */
+#[derive(Deserialize)]
+struct PackManifest {
+    // written as <output>.png and <output>.sheet.json
+    output: String,
+    #[serde(default = "default_padding")]
+    padding: u32,
+    // pixels of each frame's edge repeated around it, so filtering never samples a neighbour
+    #[serde(default = "default_extrude")]
+    extrude: u32,
+    #[serde(default = "default_max_width")]
+    max_width: u32,
+    sources: Vec<PackSource>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PackSource {
+    // .png images become one frame, .sheet.json Aseprite exports keep their frames
+    Path(String),
+    // a sheet of equal cells, split into frames left to right, top to bottom
+    Grid {
+        path: String,
+        cell_size: (u32, u32),
+        columns: u32,
+        rows: u32,
+    },
+}
+
+impl PackSource {
+    fn path(&self) -> &str {
+        match self {
+            PackSource::Path(path) | PackSource::Grid { path, .. } => path,
+        }
+    }
+}
+
+fn default_padding() -> u32 {
+    2
+}
+
+fn default_extrude() -> u32 {
+    1
+}
+
+fn default_max_width() -> u32 {
+    2048
+}
+
+struct SourceFrame {
+    name: String,
+    image: RgbaImage,
+    sprite_source_size: Rect,
+    source_size: Size,
+    duration: u32,
+}
+
+#[derive(Serialize, Clone, Copy)]
+struct Rect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Serialize, Clone, Copy)]
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutputFrame {
+    filename: String,
+    frame: Rect,
+    rotated: bool,
+    trimmed: bool,
+    sprite_source_size: Rect,
+    source_size: Size,
+    duration: u32,
+}
+
+#[derive(Serialize)]
+struct OutputTag {
+    name: String,
+    from: usize,
+    to: usize,
+    direction: String,
+}
+
+#[derive(Serialize)]
+struct OutputSlice {
+    name: String,
+    keys: Vec<OutputSliceKey>,
+}
+
+#[derive(Serialize)]
+struct OutputSliceKey {
+    frame: usize,
+    bounds: Rect,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    center: Option<Rect>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pivot: Option<Point>,
+}
+
+#[derive(Serialize)]
+struct Point {
+    x: i32,
+    y: i32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutputMeta {
+    app: &'static str,
+    image: String,
+    format: &'static str,
+    size: Size,
+    frame_tags: Vec<OutputTag>,
+    slices: Vec<OutputSlice>,
+}
+
+#[derive(Serialize)]
+struct OutputSheet {
+    frames: Vec<OutputFrame>,
+    meta: OutputMeta,
+}
+
+fn main() -> anyhow::Result<()> {
+    let manifest_path = match std::env::args().nth(1) {
+        Some(path) => PathBuf::from(path),
+        None => anyhow::bail!("usage: pack_atlas <manifest.atlas.ron>"),
+    };
+    let manifest: PackManifest = ron::de::from_bytes(&fs::read(&manifest_path)?)?;
+    let root = asset_root(&manifest_path);
+
+    let mut frames: Vec<SourceFrame> = Vec::new();
+    let mut tags = Vec::new();
+    let mut slices = Vec::new();
+    for source in manifest.sources.iter() {
+        let first = frames.len();
+        if let PackSource::Grid {
+            path,
+            cell_size,
+            columns,
+            rows,
+        } = source
+        {
+            load_grid(&root, path, *cell_size, *columns, *rows, &mut frames)?;
+        } else if source.path().ends_with(atlas::SHEET_EXTENSION) {
+            let source = source.path();
+            let meta: SpriteSheetMeta = serde_json::from_slice(&fs::read(root.join(source))?)?;
+            load_sheet(&root, source, &meta, &mut frames)?;
+            for tag in meta.meta.frame_tags.iter() {
+                tags.push(OutputTag {
+                    name: tag.name.clone(),
+                    from: first + tag.from,
+                    to: first + tag.to,
+                    direction: tag.direction.clone(),
+                });
+            }
+            for slice in meta.meta.slices.iter() {
+                slices.push(OutputSlice {
+                    name: slice.name.clone(),
+                    keys: slice
+                        .keys
+                        .iter()
+                        .map(|key| OutputSliceKey {
+                            frame: first + key.frame,
+                            bounds: rect(&key.bounds),
+                            center: key.center.as_ref().map(rect),
+                            pivot: key.pivot.map(|pivot| Point {
+                                x: pivot.x,
+                                y: pivot.y,
+                            }),
+                        })
+                        .collect(),
+                });
+            }
+        } else {
+            let source = source.path();
+            let image = image::open(root.join(source))?.to_rgba8();
+            let (w, h) = image.dimensions();
+            frames.push(SourceFrame {
+                name: source.to_string(),
+                image,
+                sprite_source_size: Rect { x: 0, y: 0, w, h },
+                source_size: Size { w, h },
+                duration: 100,
+            });
+        }
+        println!("{}: {} frame(s)", source.path(), frames.len() - first);
+    }
+
+    let border = manifest.extrude * 2 + manifest.padding;
+    let placements = pack(&frames, border, manifest.max_width)?;
+    let width = placements
+        .iter()
+        .zip(frames.iter())
+        .map(|(at, frame)| at.0 + frame.image.width() + border)
+        .max()
+        .unwrap_or(1);
+    let height = placements
+        .iter()
+        .zip(frames.iter())
+        .map(|(at, frame)| at.1 + frame.image.height() + border)
+        .max()
+        .unwrap_or(1);
+
+    let mut sheet = RgbaImage::new(width, height);
+    let mut output_frames = Vec::new();
+    for (frame, (x, y)) in frames.iter().zip(placements) {
+        let (x, y) = (x + manifest.extrude, y + manifest.extrude);
+        sheet.copy_from(&frame.image, x, y)?;
+        extrude(
+            &mut sheet,
+            x,
+            y,
+            frame.image.width(),
+            frame.image.height(),
+            manifest.extrude,
+        );
+        output_frames.push(OutputFrame {
+            filename: frame.name.clone(),
+            frame: Rect {
+                x,
+                y,
+                w: frame.image.width(),
+                h: frame.image.height(),
+            },
+            rotated: false,
+            trimmed: frame.sprite_source_size.w != frame.source_size.w
+                || frame.sprite_source_size.h != frame.source_size.h,
+            sprite_source_size: frame.sprite_source_size,
+            source_size: frame.source_size,
+            duration: frame.duration,
+        });
+    }
+
+    let output = root.join(&manifest.output);
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let image_path = output.with_extension("png");
+    sheet.save(&image_path)?;
+    let metadata = OutputSheet {
+        frames: output_frames,
+        meta: OutputMeta {
+            app: "platformer pack_atlas",
+            image: image_path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            format: "RGBA8888",
+            size: Size {
+                w: width,
+                h: height,
+            },
+            frame_tags: tags,
+            slices,
+        },
+    };
+    fs::write(
+        output.with_extension(atlas::SHEET_EXTENSION),
+        serde_json::to_string_pretty(&metadata)?,
+    )?;
+    println!(
+        "packed {} frames into {}x{}px {}",
+        frames.len(),
+        width,
+        height,
+        image_path.display()
+    );
+    Ok(())
+}
+
+// manifests live somewhere under the asset folder
+fn asset_root(manifest_path: &Path) -> PathBuf {
+    manifest_path
+        .ancestors()
+        .find(|path| path.file_name().map_or(false, |name| name == "assets"))
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("assets"))
+}
+
+fn rect(rect: &atlas::SheetRect) -> Rect {
+    Rect {
+        x: rect.x,
+        y: rect.y,
+        w: rect.w,
+        h: rect.h,
+    }
+}
+
+fn load_sheet(
+    root: &Path,
+    source: &str,
+    meta: &SpriteSheetMeta,
+    frames: &mut Vec<SourceFrame>,
+) -> anyhow::Result<()> {
+    // the image is named relative to the JSON
+    let image_path = Path::new(source).parent().map_or_else(
+        || PathBuf::from(&meta.meta.image),
+        |dir| dir.join(&meta.meta.image),
+    );
+    let sheet = image::open(root.join(image_path))?.to_rgba8();
+    for (index, frame) in meta.frames.iter().enumerate() {
+        let rect = frame.frame;
+        if rect.x + rect.w > sheet.width() || rect.y + rect.h > sheet.height() {
+            anyhow::bail!("{}: frame {} lies outside the sheet image", source, index);
+        }
+        frames.push(SourceFrame {
+            name: format!("{} {}", source, index),
+            image: sheet.view(rect.x, rect.y, rect.w, rect.h).to_image(),
+            sprite_source_size: self::rect(&frame.sprite_source_size),
+            source_size: Size {
+                w: frame.source_size.w,
+                h: frame.source_size.h,
+            },
+            duration: frame.duration,
+        });
+    }
+    Ok(())
+}
+
+fn load_grid(
+    root: &Path,
+    source: &str,
+    (w, h): (u32, u32),
+    columns: u32,
+    rows: u32,
+    frames: &mut Vec<SourceFrame>,
+) -> anyhow::Result<()> {
+    let sheet = image::open(root.join(source))?.to_rgba8();
+    if w == 0 || h == 0 || w * columns > sheet.width() || h * rows > sheet.height() {
+        anyhow::bail!(
+            "{}: {}x{} cells of {}x{}px do not fit the {}x{}px image",
+            source,
+            columns,
+            rows,
+            w,
+            h,
+            sheet.width(),
+            sheet.height()
+        );
+    }
+    for row in 0..rows {
+        for column in 0..columns {
+            frames.push(SourceFrame {
+                name: format!("{} {}", source, row * columns + column),
+                image: sheet.view(column * w, row * h, w, h).to_image(),
+                sprite_source_size: Rect { x: 0, y: 0, w, h },
+                source_size: Size { w, h },
+                duration: 100,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Shelf packing, tallest frames first. Returns the top-left corner of each frame's cell
+/// in the original order; cells are `border` pixels larger than the frame.
+fn pack(frames: &[SourceFrame], border: u32, max_width: u32) -> anyhow::Result<Vec<(u32, u32)>> {
+    let mut order: Vec<usize> = (0..frames.len()).collect();
+    order.sort_by_key(|index| std::cmp::Reverse(frames[*index].image.height()));
+
+    let mut placements = vec![(0, 0); frames.len()];
+    let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+    for index in order {
+        let (w, h) = frames[index].image.dimensions();
+        let (w, h) = (w + border, h + border);
+        if w > max_width {
+            anyhow::bail!(
+                "{} is wider than the {}px atlas",
+                frames[index].name,
+                max_width
+            );
+        }
+        if x + w > max_width {
+            x = 0;
+            y += shelf_height;
+            shelf_height = 0;
+        }
+        placements[index] = (x, y);
+        x += w;
+        shelf_height = shelf_height.max(h);
+    }
+    Ok(placements)
+}
+
+/// Repeats the outermost pixels of the frame at (x, y) `amount` pixels outwards.
+fn extrude(sheet: &mut RgbaImage, x: u32, y: u32, w: u32, h: u32, amount: u32) {
+    if w == 0 || h == 0 {
+        return;
+    }
+    for step in 1..=amount {
+        for column in 0..w {
+            let top = *sheet.get_pixel(x + column, y);
+            let bottom = *sheet.get_pixel(x + column, y + h - 1);
+            sheet.put_pixel(x + column, y - step, top);
+            sheet.put_pixel(x + column, y + h - 1 + step, bottom);
+        }
+        for row in 0..h {
+            let left = *sheet.get_pixel(x, y + row);
+            let right = *sheet.get_pixel(x + w - 1, y + row);
+            sheet.put_pixel(x - step, y + row, left);
+            sheet.put_pixel(x + w - 1 + step, y + row, right);
+        }
+        // corners
+        for (corner_x, corner_y, dx, dy) in [
+            (x, y, -1i32, -1i32),
+            (x + w - 1, y, 1, -1),
+            (x, y + h - 1, -1, 1),
+            (x + w - 1, y + h - 1, 1, 1),
+        ] {
+            let pixel = *sheet.get_pixel(corner_x, corner_y);
+            for i in 1..=step {
+                for j in 1..=step {
+                    sheet.put_pixel(
+                        (corner_x as i32 + dx * i as i32) as u32,
+                        (corner_y as i32 + dy * j as i32) as u32,
+                        pixel,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_are_paths_or_grids() {
+        let manifest: PackManifest = ron::de::from_str(
+            r#"(
+                output: "atlases/test",
+                sources: [
+                    "walk.sheet.json",
+                    (path: "grid.png", cell_size: (69, 44), columns: 6, rows: 17),
+                ],
+            )"#,
+        )
+        .unwrap();
+        assert!(
+            matches!(&manifest.sources[0], PackSource::Path(path) if path == "walk.sheet.json")
+        );
+        assert!(matches!(
+            &manifest.sources[1],
+            PackSource::Grid {
+                cell_size: (69, 44),
+                columns: 6,
+                rows: 17,
+                ..
+            }
+        ));
+    }
+}
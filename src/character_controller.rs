@@ -0,0 +1,426 @@
+use bevy::prelude::*;
+
+use crate::animated_sprite::KeyBinds;
+use crate::collision::{Collider, CollisionGrid, SurfaceMaterial, SurfaceMaterials};
+use crate::combat::HitStun;
+use crate::simulation::{
+    simulation_delta_seconds, Position, SimulationStage, StorePreviousPositions,
+};
+use crate::Player;
+
+pub struct CharacterControllerPlugin;
+
+impl Plugin for CharacterControllerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<KeyBinds>()
+            .add_system(player_input.label(ControllerSystem::Input))
+            .add_system_to_stage(
+                SimulationStage,
+                move_characters
+                    .label(ControllerSystem::Movement)
+                    .after(StorePreviousPositions),
+            );
+    }
+}
+
+// landing slower than this on a bouncy surface just lands
+const MIN_BOUNCE_SPEED: f32 = 100.0;
+
+// rise per pixel moved sideways on the steepest (45°) slopes
+const MAX_SLOPE_GRADIENT: f32 = 1.0;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum ControllerSystem {
+    Input,
+    Abilities,
+    Movement,
+}
+
+#[derive(Component)]
+pub struct CharacterController {
+    pub speed: f32,
+    // how fast the horizontal speed reaches its target, scaled by the surface friction
+    pub acceleration: f32,
+    pub jump_speed: f32,
+    pub gravity: f32,
+    pub max_fall_speed: f32,
+    // how far above the feet a floor may be and still be walked onto
+    pub step_height: f32,
+    // how far below the feet the floor is followed while grounded
+    pub snap_distance: f32,
+    pub move_direction: f32,
+    pub vertical_input: f32,
+    // -1.0 when facing left, 1.0 when facing right
+    pub facing: f32,
+    pub jump_requested: bool,
+    pub dash_requested: bool,
+    // seconds left during which `move_direction` is ignored and the horizontal velocity kept
+    pub input_lock: f32,
+    pub gravity_scale: f32,
+    // fall speed cap for the current frame only, e.g. while wall sliding
+    pub fall_speed_limit: Option<f32>,
+    pub grounded: bool,
+    pub on_slope: bool,
+    // material of the floor while grounded, for footsteps and particles
+    pub surface: Option<SurfaceMaterial>,
+    // -1.0 / 1.0 when touching a wall on the left / right
+    pub wall: Option<f32>,
+}
+
+impl Default for CharacterController {
+    fn default() -> Self {
+        CharacterController {
+            speed: 200.0,
+            acceleration: 4000.0,
+            jump_speed: 420.0,
+            gravity: 1200.0,
+            max_fall_speed: 800.0,
+            step_height: 4.0,
+            snap_distance: 8.0,
+            move_direction: 0.0,
+            vertical_input: 0.0,
+            facing: 1.0,
+            jump_requested: false,
+            dash_requested: false,
+            input_lock: 0.0,
+            gravity_scale: 1.0,
+            fall_speed_limit: None,
+            grounded: false,
+            on_slope: false,
+            surface: None,
+            wall: None,
+        }
+    }
+}
+
+fn player_input(
+    input: Res<Input<KeyCode>>,
+    keybinds: Res<KeyBinds>,
+    mut query: Query<
+        (
+            &mut CharacterController,
+            &mut TextureAtlasSprite,
+            Option<&HitStun>,
+        ),
+        With<Player>,
+    >,
+) {
+    for (mut controller, mut sprite, hit_stun) in query.iter_mut() {
+        if hit_stun.is_some() {
+            controller.move_direction = 0.0;
+            controller.vertical_input = 0.0;
+            controller.jump_requested = false;
+            controller.dash_requested = false;
+            continue;
+        }
+
+        let mut direction = 0.0;
+        if input.pressed(keybinds.move_left) {
+            direction -= 1.0;
+        }
+        if input.pressed(keybinds.move_right) {
+            direction += 1.0;
+        }
+        if direction != 0.0 && controller.input_lock <= 0.0 {
+            controller.facing = direction;
+            sprite.flip_x = direction < 0.0;
+        }
+
+        let mut vertical = 0.0;
+        if input.pressed(keybinds.move_up) {
+            vertical += 1.0;
+        }
+        if input.pressed(keybinds.move_down) {
+            vertical -= 1.0;
+        }
+
+        controller.move_direction = direction;
+        controller.vertical_input = vertical;
+        // requests stay set until a simulation step consumes them
+        controller.jump_requested |= input.just_pressed(keybinds.jump);
+        controller.dash_requested |= input.just_pressed(keybinds.dash);
+    }
+}
+
+pub fn move_characters(
+    grid: Res<CollisionGrid>,
+    materials: Res<SurfaceMaterials>,
+    mut query: Query<(&mut CharacterController, &mut Position, &Collider)>,
+) {
+    let dt = simulation_delta_seconds();
+    for (mut controller, mut body, collider) in query.iter_mut() {
+        let mut velocity = body.velocity;
+        // horizontal speed stays the same on flat ground and slopes, the floor snap below
+        // takes care of following the surface
+        let surface = controller.surface.unwrap_or_default();
+        if controller.input_lock > 0.0 {
+            controller.input_lock -= dt;
+        } else {
+            let mut target = controller.move_direction * controller.speed;
+            let mut acceleration = controller.acceleration;
+            if controller.grounded {
+                target = target * surface.speed_multiplier + surface.conveyor;
+                acceleration *= surface.friction;
+            }
+            let step = acceleration * dt;
+            velocity.x += (target - velocity.x).clamp(-step, step);
+        }
+
+        if controller.grounded && controller.jump_requested {
+            velocity.y = controller.jump_speed;
+            controller.grounded = false;
+        } else if !controller.grounded {
+            let gravity = controller.gravity * controller.gravity_scale;
+            let max_fall_speed = controller
+                .fall_speed_limit
+                .unwrap_or(controller.max_fall_speed);
+            velocity.y = (velocity.y - gravity * dt).max(-max_fall_speed);
+        } else {
+            // only upward impulses (e.g. a dash) leave the ground
+            velocity.y = velocity.y.max(0.0);
+        }
+        controller.jump_requested = false;
+        controller.dash_requested = false;
+        controller.fall_speed_limit = None;
+
+        let delta = velocity * dt;
+        let mut position = body.current;
+        let was_grounded = controller.grounded;
+        let landing_speed = -velocity.y;
+        // walking up a slope raises the feet by up to its gradient times the distance moved,
+        // which is more than the step height at dash speeds
+        let slope_rise = delta.x.abs() * MAX_SLOPE_GRADIENT;
+
+        // Horizontal pass
+        position.x += delta.x;
+        let (min, max) = aabb(position, collider);
+        let mut step_top = min.y + controller.step_height;
+        // the cells filling in under the slope ahead are part of it
+        if was_grounded && controller.on_slope {
+            step_top += slope_rise;
+        }
+        for (x, y) in grid.solid_cells_in(min, max) {
+            let (cell_min, cell_max) = grid.cell_rect(x, y);
+            if cell_max.y <= step_top || cell_max.y <= min.y || cell_min.y >= max.y {
+                continue;
+            }
+            if delta.x > 0.0 {
+                position.x = position.x.min(cell_min.x - collider.half_size.x);
+            } else if delta.x < 0.0 {
+                position.x = position.x.max(cell_max.x + collider.half_size.x);
+            }
+            velocity.x = 0.0;
+        }
+        controller.wall = wall_contact(&grid, position, collider, controller.step_height);
+
+        // Vertical pass
+        let old_bottom = position.y - collider.half_size.y;
+        let old_top = position.y + collider.half_size.y;
+        position.y += delta.y;
+        controller.grounded = false;
+        controller.on_slope = false;
+        controller.surface = None;
+
+        if delta.y <= 0.0 {
+            let mut lowest = position.y - collider.half_size.y;
+            if was_grounded {
+                lowest -= controller.snap_distance;
+            }
+            let highest = old_bottom + controller.step_height;
+            // full cells still have to be within a step
+            let slope_highest = if was_grounded {
+                highest + slope_rise
+            } else {
+                highest
+            };
+            // slopes are followed from the center of the feet, full cells from either edge
+            let mut floor = grid
+                .floor_at(position.x, lowest, slope_highest)
+                .filter(|floor| floor.tile.is_slope() || floor.height <= highest)
+                .or_else(|| grid.floor_at(position.x, lowest, highest));
+            for edge in [
+                position.x - collider.half_size.x,
+                position.x + collider.half_size.x,
+            ] {
+                if let Some(edge_floor) = grid.floor_at(edge, lowest, highest) {
+                    if edge_floor.tile.is_solid()
+                        && floor.map_or(true, |current| edge_floor.height > current.height)
+                    {
+                        floor = Some(edge_floor);
+                    }
+                }
+            }
+            if let Some(floor) = floor {
+                let material = materials.get(floor.value);
+                position.y = floor.height + collider.half_size.y;
+                if !was_grounded && material.restitution > 0.0 && landing_speed > MIN_BOUNCE_SPEED {
+                    velocity.y = landing_speed * material.restitution;
+                } else {
+                    velocity.y = 0.0;
+                    controller.grounded = true;
+                    controller.on_slope = floor.tile.is_slope();
+                    controller.surface = Some(material);
+                }
+            }
+        } else {
+            let (min, max) = aabb(position, collider);
+            for (x, y) in grid.solid_cells_in(min, max) {
+                let (cell_min, _) = grid.cell_rect(x, y);
+                if cell_min.y < old_top {
+                    continue;
+                }
+                position.y = position.y.min(cell_min.y - collider.half_size.y);
+                velocity.y = 0.0;
+            }
+        }
+
+        body.current = position;
+        body.velocity = velocity;
+    }
+}
+
+fn wall_contact(
+    grid: &CollisionGrid,
+    position: Vec2,
+    collider: &Collider,
+    step_height: f32,
+) -> Option<f32> {
+    let (min, max) = aabb(position, collider);
+    for side in [-1.0, 1.0] {
+        let probe_x = if side < 0.0 { min.x - 1.0 } else { max.x + 1.0 };
+        let probe_min = Vec2::new(probe_x, min.y + step_height + 0.01);
+        let probe_max = Vec2::new(probe_x, max.y - 0.01);
+        if !grid.solid_cells_in(probe_min, probe_max).is_empty() {
+            return Some(side);
+        }
+    }
+    None
+}
+
+pub fn aabb(position: Vec2, collider: &Collider) -> (Vec2, Vec2) {
+    (position - collider.half_size, position + collider.half_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::SurfaceKind;
+
+    const DASH_SPEED: f32 = 600.0;
+
+    // a character standing at `position` on `csv`, cells 16px with the top-left at the origin
+    fn world_with(csv: &str, position: Vec2) -> (World, Entity) {
+        let mut world = World::new();
+        world.insert_resource(CollisionGrid::from_csv(csv, 16.0, Vec2::ZERO));
+        world.insert_resource(SurfaceMaterials::default());
+        let entity = world
+            .spawn()
+            .insert(Position::new(position))
+            .insert(CharacterController {
+                grounded: true,
+                ..Default::default()
+            })
+            .insert(Collider {
+                half_size: Vec2::new(4.0, 8.0),
+            })
+            .id();
+        (world, entity)
+    }
+
+    fn step(world: &mut World, steps: usize) {
+        let mut stage = SystemStage::single_threaded().with_system(move_characters);
+        for _ in 0..steps {
+            stage.run(world);
+        }
+    }
+
+    #[test]
+    fn dashing_up_a_slope_follows_it() {
+        // flat ground, then a 45° slope rising to the right over five cells
+        let csv = "\
+            0,0,0,0,0,0,0,0,0,0\n\
+            0,0,0,0,0,0,0,0,0,2\n\
+            0,0,0,0,0,0,0,0,2,1\n\
+            0,0,0,0,0,0,0,2,1,1\n\
+            0,0,0,0,0,0,2,1,1,1\n\
+            0,0,0,0,0,2,1,1,1,1\n\
+            1,1,1,1,1,1,1,1,1,1\n";
+        let (mut world, entity) = world_with(csv, Vec2::new(24.0, -88.0));
+        {
+            let mut controller = world.get_mut::<CharacterController>(entity).unwrap();
+            // as a dash leaves it
+            controller.input_lock = 1.0;
+            controller.gravity_scale = 0.0;
+        }
+        world.get_mut::<Position>(entity).unwrap().velocity.x = DASH_SPEED;
+
+        let mut climbed = false;
+        for _ in 0..12 {
+            step(&mut world, 1);
+            let position = world.get::<Position>(entity).unwrap().current;
+            let controller = world.get::<CharacterController>(entity).unwrap();
+            if position.x > 96.0 && position.x < 152.0 {
+                // on the slope surface: the feet are where the slope is under the centre
+                assert!(
+                    controller.grounded,
+                    "fell off the slope at x = {}",
+                    position.x
+                );
+                assert!(controller.on_slope);
+                let surface = -96.0 + (position.x - 80.0);
+                assert!((position.y - 8.0 - surface).abs() < 0.001);
+                climbed = true;
+            }
+        }
+        assert!(climbed);
+    }
+
+    #[test]
+    fn dashing_does_not_climb_walls() {
+        let csv = "\
+            0,0,0,0,0,0\n\
+            0,0,0,0,1,1\n\
+            1,1,1,1,1,1\n";
+        let (mut world, entity) = world_with(csv, Vec2::new(24.0, -24.0));
+        world
+            .get_mut::<CharacterController>(entity)
+            .unwrap()
+            .input_lock = 1.0;
+        world.get_mut::<Position>(entity).unwrap().velocity.x = DASH_SPEED;
+        step(&mut world, 10);
+
+        let position = world.get::<Position>(entity).unwrap().current;
+        assert_eq!(position, Vec2::new(60.0, -24.0));
+    }
+
+    #[test]
+    fn surfaces_change_how_characters_move() {
+        // plain ground, ice and a conveyor moving right, one cell each
+        let csv = "0,0,0\n1,8,10\n";
+        let run = |x: f32, steps: usize| {
+            let (mut world, entity) = world_with(csv, Vec2::new(x, -8.0));
+            world
+                .get_mut::<CharacterController>(entity)
+                .unwrap()
+                .move_direction = 1.0;
+            step(&mut world, steps);
+            let controller = world.get::<CharacterController>(entity).unwrap();
+            let kind = controller.surface.map(|surface| surface.kind);
+            (world.get::<Position>(entity).unwrap().velocity.x, kind)
+        };
+
+        // the first step has no surface under it yet, so compare from the second on
+        let (ground, kind) = run(8.0, 2);
+        assert_eq!(kind, Some(SurfaceKind::Default));
+        let (ice, kind) = run(24.0, 2);
+        assert_eq!(kind, Some(SurfaceKind::Ice));
+        let first_step = run(8.0, 1).0;
+        // ice only picks up a fraction of the speed per step
+        assert!(ice > first_step && ice - first_step < (ground - first_step) / 10.0);
+        // the conveyor adds to the target speed
+        let (conveyor, kind) = run(40.0, 4);
+        assert_eq!(kind, Some(SurfaceKind::Conveyor));
+        let (ground, _) = run(8.0, 4);
+        assert!(conveyor > ground);
+    }
+}
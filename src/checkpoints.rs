@@ -0,0 +1,207 @@
+use bevy::prelude::*;
+
+use crate::character_controller::ControllerSystem;
+use crate::collision::Collider;
+use crate::doors::{run_transition, Facing, LevelTransition, TransitionPhase};
+use crate::level_entities::{EntityBounds, LevelEntity, RegisterLdtkEntityExt};
+use crate::level_streaming::{LevelWorld, START_LEVEL};
+use crate::simulation::Position;
+use crate::{ldtk_component, BaseEntityStates, EntityAnimations, Life, Player};
+
+// seconds the player lies dead before the screen fades out
+const RESPAWN_DELAY: f32 = 1.5;
+
+pub struct CheckpointsPlugin;
+
+impl Plugin for CheckpointsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<CheckpointActivated>()
+            .add_event::<PlayerRespawned>()
+            .add_ldtk_component::<Checkpoint>("Checkpoint")
+            .add_system(start_checkpoint)
+            .add_system(activate_checkpoints.after(ControllerSystem::Input))
+            .add_system(respawn_player.before(run_transition))
+            .add_system(finish_respawn.after(run_transition));
+    }
+}
+
+/* Checkpoints, placed in LDtk as "Checkpoint" entities. Touching one makes it the
+place the player comes back to after dying and saves its level's state; respawning
+fades out, loads that level again from the saved state (so enemies defeated since come
+back) and puts the player on the checkpoint with full life. Before any checkpoint is
+reached, the player comes back where they first appeared.
+ */
+ldtk_component! {
+    #[derive(Component)]
+    pub struct Checkpoint {
+        pub facing: Option<Facing>,
+    }
+}
+
+/// Where the player respawns.
+pub struct ActiveCheckpoint {
+    pub level: String,
+    // iid of the checkpoint entity, None for the starting position
+    pub iid: Option<String>,
+    // where the player's feet go
+    pub position: Vec2,
+    pub facing: Option<Facing>,
+}
+
+/// Sent when the player touches a checkpoint that was not the active one.
+pub struct CheckpointActivated {
+    pub checkpoint: Entity,
+    pub level: String,
+    pub iid: String,
+    pub position: Vec2,
+}
+
+/// Sent once a respawned player is back on the checkpoint and the screen fades in.
+pub struct PlayerRespawned {
+    pub player: Entity,
+    pub level: String,
+}
+
+// life the player comes back with, what they had when they first appeared
+#[derive(Component, Deref)]
+struct RespawnLife(u8);
+
+#[derive(Component)]
+struct Respawning;
+
+fn start_checkpoint(
+    mut commands: Commands,
+    active: Option<Res<ActiveCheckpoint>>,
+    world: Option<Res<LevelWorld>>,
+    players: Query<(Entity, &Position, &Collider, &Life), Added<Player>>,
+) {
+    for (entity, position, collider, life) in players.iter() {
+        commands.entity(entity).insert(RespawnLife(**life));
+        if active.is_none() {
+            commands.insert_resource(ActiveCheckpoint {
+                level: world
+                    .as_ref()
+                    .map_or(START_LEVEL.to_string(), |world| world.current.clone()),
+                iid: None,
+                position: position.current - Vec2::new(0.0, collider.half_size.y),
+                facing: None,
+            });
+        }
+    }
+}
+
+fn activate_checkpoints(
+    mut events: EventWriter<CheckpointActivated>,
+    active: Option<ResMut<ActiveCheckpoint>>,
+    world: Option<ResMut<LevelWorld>>,
+    players: Query<(&Position, &Collider, &Life), With<Player>>,
+    checkpoints: Query<(Entity, &Checkpoint, &EntityBounds, &LevelEntity)>,
+) {
+    let (mut active, mut world) = match (active, world) {
+        (Some(active), Some(world)) => (active, world),
+        _ => return,
+    };
+    let (position, collider) = match players.get_single() {
+        Ok((position, collider, life)) if **life > 0 => (position, collider),
+        _ => return,
+    };
+    let touched = checkpoints
+        .iter()
+        .find(|(_, _, bounds, _)| bounds.overlaps(position.current, collider.half_size));
+    let (entity, checkpoint, bounds, level_entity) = match touched {
+        Some(touched) => touched,
+        None => return,
+    };
+    if active.iid.as_deref() == Some(level_entity.iid.as_str()) {
+        return;
+    }
+
+    let bottom_center = Vec2::new((bounds.min.x + bounds.max.x) / 2.0, bounds.min.y);
+    *active = ActiveCheckpoint {
+        level: level_entity.level.clone(),
+        iid: Some(level_entity.iid.clone()),
+        position: bottom_center,
+        facing: checkpoint.facing,
+    };
+    world.save(&level_entity.level);
+    events.send(CheckpointActivated {
+        checkpoint: entity,
+        level: level_entity.level.clone(),
+        iid: level_entity.iid.clone(),
+        position: bottom_center,
+    });
+}
+
+fn respawn_player(
+    mut commands: Commands,
+    time: Res<Time>,
+    active: Option<Res<ActiveCheckpoint>>,
+    transition: Option<Res<LevelTransition>>,
+    players: Query<(Entity, &Life), (With<Player>, Without<Respawning>)>,
+    mut dead_for: Local<f32>,
+) {
+    let (player, life) = match players.get_single() {
+        Ok(player) => player,
+        Err(_) => return,
+    };
+    if **life > 0 {
+        *dead_for = 0.0;
+        return;
+    }
+    *dead_for += time.delta_seconds();
+    let active = match active {
+        Some(active) => active,
+        None => return,
+    };
+    if *dead_for < RESPAWN_DELAY || transition.is_some() {
+        return;
+    }
+
+    *dead_for = 0.0;
+    commands.entity(player).insert(Respawning);
+    commands.insert_resource(LevelTransition {
+        phase: TransitionPhase::FadeOut,
+        level: active.level.clone(),
+        entry: active.position,
+        facing: active.facing,
+        fade: 0.0,
+        reload: true,
+    });
+}
+
+fn finish_respawn(
+    mut commands: Commands,
+    mut events: EventWriter<PlayerRespawned>,
+    active: Option<Res<ActiveCheckpoint>>,
+    transition: Option<Res<LevelTransition>>,
+    mut players: Query<
+        (
+            Entity,
+            &mut Life,
+            Option<&RespawnLife>,
+            Option<&mut EntityAnimations>,
+        ),
+        (With<Player>, With<Respawning>),
+    >,
+) {
+    // the player is put back on the checkpoint when the level has loaded
+    if matches!(
+        transition.map(|transition| transition.phase),
+        Some(TransitionPhase::FadeOut) | Some(TransitionPhase::Loading)
+    ) {
+        return;
+    }
+    for (entity, mut life, respawn_life, animations) in players.iter_mut() {
+        **life = respawn_life.map_or(1, |respawn_life| **respawn_life);
+        if let Some(mut animations) = animations {
+            animations.update_state(BaseEntityStates::Idle);
+        }
+        commands.entity(entity).remove::<Respawning>();
+        events.send(PlayerRespawned {
+            player: entity,
+            level: active
+                .as_ref()
+                .map_or(START_LEVEL.to_string(), |active| active.level.clone()),
+        });
+    }
+}
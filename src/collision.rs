@@ -0,0 +1,320 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::asset_check::{asset_root, AssetManifest};
+
+pub const LEVEL_INTGRID_CSV: &str = "Levels/basic/simplified/Basic_1/IntGrid.csv";
+
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.world
+            .get_resource_or_insert_with(AssetManifest::default)
+            .add("collision grid", LEVEL_INTGRID_CSV);
+        app.insert_resource(CollisionGrid::default())
+            .init_resource::<SurfaceMaterials>()
+            .add_startup_system(load_collision_grid);
+    }
+}
+
+/* IntGrid values as painted in LDtk:
+- 0: empty
+- 1: solid
+- 2/3: 45° slopes rising to the right / left
+- 4/5: 22.5° half-slopes rising to the right (low half, high half)
+- 6/7: 22.5° half-slopes rising to the left (high half, low half)
+- 8: ice
+- 9/10: conveyors moving to the left / right
+- 11: bouncy
+- 12: sticky
+Anything else is a plain solid cell.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileKind {
+    Empty,
+    Solid,
+    // floor heights at the left and right edge of the cell, as a fraction of the cell size
+    Slope { left: f32, right: f32 },
+}
+
+impl TileKind {
+    pub fn from_intgrid(value: i32) -> Self {
+        match value {
+            0 => TileKind::Empty,
+            2 => TileKind::Slope {
+                left: 0.0,
+                right: 1.0,
+            },
+            3 => TileKind::Slope {
+                left: 1.0,
+                right: 0.0,
+            },
+            4 => TileKind::Slope {
+                left: 0.0,
+                right: 0.5,
+            },
+            5 => TileKind::Slope {
+                left: 0.5,
+                right: 1.0,
+            },
+            6 => TileKind::Slope {
+                left: 1.0,
+                right: 0.5,
+            },
+            7 => TileKind::Slope {
+                left: 0.5,
+                right: 0.0,
+            },
+            _ => TileKind::Solid,
+        }
+    }
+
+    pub fn is_solid(&self) -> bool {
+        *self == TileKind::Solid
+    }
+
+    pub fn is_slope(&self) -> bool {
+        matches!(self, TileKind::Slope { .. })
+    }
+
+    // floor height at `t` (0.0 = left edge, 1.0 = right edge), as a fraction of the cell size
+    pub fn surface_at(&self, t: f32) -> Option<f32> {
+        match self {
+            TileKind::Empty => None,
+            TileKind::Solid => Some(1.0),
+            TileKind::Slope { left, right } => Some(left + (right - left) * t.clamp(0.0, 1.0)),
+        }
+    }
+}
+
+/// IntGrid collision layer. Cell (0, 0) is the top-left cell, like in LDtk,
+/// and `origin` is the world position of that cell's top-left corner.
+pub struct CollisionGrid {
+    pub width: usize,
+    pub height: usize,
+    pub cell_size: f32,
+    pub origin: Vec2,
+    pub values: Vec<i32>,
+}
+
+impl Default for CollisionGrid {
+    fn default() -> Self {
+        CollisionGrid {
+            width: 0,
+            height: 0,
+            cell_size: 16.0,
+            origin: Vec2::ZERO,
+            values: Vec::new(),
+        }
+    }
+}
+
+impl CollisionGrid {
+    pub fn from_csv(csv: &str, cell_size: f32, origin: Vec2) -> Self {
+        let mut width = 0;
+        let mut values = Vec::new();
+        for line in csv.lines().filter(|line| !line.trim().is_empty()) {
+            let row: Vec<i32> = line
+                .split(',')
+                .filter(|value| !value.trim().is_empty())
+                .map(|value| value.trim().parse().unwrap_or(0))
+                .collect();
+            width = width.max(row.len());
+            values.extend(row);
+        }
+        let height = if width == 0 { 0 } else { values.len() / width };
+
+        CollisionGrid {
+            width,
+            height,
+            cell_size,
+            origin,
+            values,
+        }
+    }
+
+    pub fn value(&self, x: i32, y: i32) -> i32 {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return 0;
+        }
+        self.values[y as usize * self.width + x as usize]
+    }
+
+    pub fn tile(&self, x: i32, y: i32) -> TileKind {
+        TileKind::from_intgrid(self.value(x, y))
+    }
+
+    pub fn cell_at(&self, position: Vec2) -> (i32, i32) {
+        (
+            ((position.x - self.origin.x) / self.cell_size).floor() as i32,
+            ((self.origin.y - position.y) / self.cell_size).floor() as i32,
+        )
+    }
+
+    // world rect of a cell as (min, max)
+    pub fn cell_rect(&self, x: i32, y: i32) -> (Vec2, Vec2) {
+        let min = Vec2::new(
+            self.origin.x + x as f32 * self.cell_size,
+            self.origin.y - (y + 1) as f32 * self.cell_size,
+        );
+        (min, min + Vec2::splat(self.cell_size))
+    }
+
+    /// Highest floor under `x` whose surface lies between `min_y` and `max_y`.
+    pub fn floor_at(&self, x: f32, min_y: f32, max_y: f32) -> Option<Floor> {
+        let (cell_x, top) = self.cell_at(Vec2::new(x, max_y));
+        let (_, bottom) = self.cell_at(Vec2::new(x, min_y));
+        for cell_y in top..=bottom {
+            let tile = self.tile(cell_x, cell_y);
+            let (min, _) = self.cell_rect(cell_x, cell_y);
+            let t = (x - min.x) / self.cell_size;
+            if let Some(surface) = tile.surface_at(t) {
+                let surface = min.y + surface * self.cell_size;
+                if surface <= max_y && surface >= min_y {
+                    return Some(Floor {
+                        height: surface,
+                        tile,
+                        value: self.value(cell_x, cell_y),
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Solid cells overlapping the given box. Slopes are left to `floor_at`.
+    pub fn solid_cells_in(&self, min: Vec2, max: Vec2) -> Vec<(i32, i32)> {
+        let (min_x, max_y) = self.cell_at(Vec2::new(min.x, min.y));
+        let (max_x, min_y) = self.cell_at(Vec2::new(max.x, max.y));
+        let mut cells = Vec::new();
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                if self.tile(x, y).is_solid() {
+                    cells.push((x, y));
+                }
+            }
+        }
+        cells
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Floor {
+    pub height: f32,
+    pub tile: TileKind,
+    // IntGrid value of the cell, used to look up its `SurfaceMaterial`
+    pub value: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SurfaceKind {
+    Default,
+    Ice,
+    Conveyor,
+    Bouncy,
+    Sticky,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SurfaceMaterial {
+    pub kind: SurfaceKind,
+    // scales how fast characters reach their target speed, 1.0 is normal ground
+    pub friction: f32,
+    // horizontal speed added to anything standing on the surface
+    pub conveyor: f32,
+    // fraction of the landing speed bounced back up
+    pub restitution: f32,
+    pub speed_multiplier: f32,
+}
+
+impl Default for SurfaceMaterial {
+    fn default() -> Self {
+        SurfaceMaterial {
+            kind: SurfaceKind::Default,
+            friction: 1.0,
+            conveyor: 0.0,
+            restitution: 0.0,
+            speed_multiplier: 1.0,
+        }
+    }
+}
+
+/// Surface materials keyed by IntGrid value. Values without an entry use the default material.
+pub struct SurfaceMaterials {
+    pub materials: HashMap<i32, SurfaceMaterial>,
+    pub fallback: SurfaceMaterial,
+}
+
+impl Default for SurfaceMaterials {
+    fn default() -> Self {
+        let mut materials = HashMap::default();
+        materials.insert(
+            8,
+            SurfaceMaterial {
+                kind: SurfaceKind::Ice,
+                friction: 0.05,
+                ..Default::default()
+            },
+        );
+        for (value, conveyor) in [(9, -80.0), (10, 80.0)] {
+            materials.insert(
+                value,
+                SurfaceMaterial {
+                    kind: SurfaceKind::Conveyor,
+                    conveyor,
+                    ..Default::default()
+                },
+            );
+        }
+        materials.insert(
+            11,
+            SurfaceMaterial {
+                kind: SurfaceKind::Bouncy,
+                restitution: 0.8,
+                ..Default::default()
+            },
+        );
+        materials.insert(
+            12,
+            SurfaceMaterial {
+                kind: SurfaceKind::Sticky,
+                speed_multiplier: 0.4,
+                ..Default::default()
+            },
+        );
+
+        SurfaceMaterials {
+            materials,
+            fallback: SurfaceMaterial::default(),
+        }
+    }
+}
+
+impl SurfaceMaterials {
+    pub fn get(&self, value: i32) -> SurfaceMaterial {
+        self.materials.get(&value).copied().unwrap_or(self.fallback)
+    }
+}
+
+/// Axis-aligned box around the entity's translation.
+#[derive(Component, Clone, Copy)]
+pub struct Collider {
+    pub half_size: Vec2,
+}
+
+fn load_collision_grid(mut grid: ResMut<CollisionGrid>) {
+    match std::fs::read_to_string(asset_root().join(LEVEL_INTGRID_CSV)) {
+        Ok(csv) => {
+            let cell_size = grid.cell_size;
+            let mut loaded = CollisionGrid::from_csv(&csv, cell_size, Vec2::ZERO);
+            // center the level on the world origin
+            loaded.origin = Vec2::new(
+                -(loaded.width as f32 * cell_size) / 2.0,
+                (loaded.height as f32 * cell_size) / 2.0,
+            );
+            *grid = loaded;
+        }
+        Err(err) => warn!("could not load {}: {}", LEVEL_INTGRID_CSV, err),
+    }
+}
@@ -0,0 +1,132 @@
+use bevy::prelude::*;
+
+use crate::abilities::Invulnerable;
+use crate::character_controller::{CharacterController, ControllerSystem};
+use crate::simulation::{simulation_delta, simulation_delta_seconds, Position, SimulationStage};
+use crate::{BaseEntityStates, EntityAnimations, Life};
+
+pub struct CombatPlugin;
+
+impl Plugin for CombatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DamageEvent>()
+            .init_resource::<PendingDamage>()
+            .add_system(queue_damage)
+            .add_system_to_stage(
+                SimulationStage,
+                apply_damage.before(ControllerSystem::Movement),
+            )
+            .add_system_to_stage(
+                SimulationStage,
+                update_hit_stun
+                    .after(apply_damage)
+                    .before(ControllerSystem::Movement),
+            );
+    }
+}
+
+/// Damage dealt to `target`. The knockback impulse replaces the target's velocity
+/// and is resolved by the character controller, so it never pushes through tiles.
+#[derive(Clone)]
+pub struct DamageEvent {
+    pub target: Entity,
+    pub amount: u8,
+    pub knockback: Vec2,
+    // seconds during which the target ignores input
+    pub stun: f32,
+}
+
+#[derive(Component)]
+pub struct HitStun {
+    pub timer: Timer,
+    // fraction of the horizontal knockback lost per second
+    pub friction: f32,
+}
+
+impl HitStun {
+    pub fn new(seconds: f32) -> Self {
+        HitStun {
+            timer: Timer::from_seconds(seconds, false),
+            friction: 4.0,
+        }
+    }
+}
+
+// events only live for two frames, which the fixed step can skip at high frame rates,
+// so damage is read every frame and queued until the next step
+#[derive(Default)]
+struct PendingDamage(Vec<DamageEvent>);
+
+fn queue_damage(mut pending: ResMut<PendingDamage>, mut damage_events: EventReader<DamageEvent>) {
+    pending.0.extend(damage_events.iter().cloned());
+}
+
+fn apply_damage(
+    mut commands: Commands,
+    mut pending: ResMut<PendingDamage>,
+    mut query: Query<
+        (
+            &mut Life,
+            Option<&mut Position>,
+            Option<&mut CharacterController>,
+            Option<&mut EntityAnimations>,
+        ),
+        Without<Invulnerable>,
+    >,
+) {
+    for event in pending.0.drain(..) {
+        let (mut life, position, controller, animations) = match query.get_mut(event.target) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        **life = life.saturating_sub(event.amount);
+
+        if let Some(mut position) = position {
+            position.velocity = event.knockback;
+        }
+        if let Some(mut controller) = controller {
+            controller.input_lock = event.stun;
+            controller.jump_requested = false;
+            controller.dash_requested = false;
+            if event.knockback.y > 0.0 {
+                controller.grounded = false;
+            }
+        }
+        if let Some(mut animations) = animations {
+            if **life == 0 {
+                animations.update_state(BaseEntityStates::Death);
+            } else {
+                animations.update_state(BaseEntityStates::OnHit);
+            }
+        }
+        commands
+            .entity(event.target)
+            .insert(HitStun::new(event.stun));
+    }
+}
+
+fn update_hit_stun(
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &mut HitStun,
+        &Life,
+        Option<&mut Position>,
+        Option<&mut EntityAnimations>,
+    )>,
+) {
+    let dt = simulation_delta_seconds();
+    for (entity, mut hit_stun, life, position, animations) in query.iter_mut() {
+        if let Some(mut position) = position {
+            position.velocity.x *= (1.0 - hit_stun.friction * dt).max(0.0);
+        }
+        if hit_stun.timer.tick(simulation_delta()).finished() {
+            commands.entity(entity).remove::<HitStun>();
+            if let Some(mut animations) = animations {
+                if **life > 0 {
+                    animations.update_state(BaseEntityStates::Idle);
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use bevy::prelude::*;
+use bevy::transform::TransformSystem;
 
 use bevy_parallax::{
     LayerData, ParallaxCameraComponent, ParallaxMoveEvent, ParallaxPlugin, ParallaxResource,
 };
 
+use crate::render_layers::RenderLayer;
+
 pub struct CustomParallaxPlugin;
 impl Plugin for CustomParallaxPlugin {
     fn build(&self, app: &mut App) {
@@ -17,7 +20,7 @@ impl Plugin for CustomParallaxPlugin {
                     cols: 1,
                     rows: 1,
                     scale: 6.0,
-                    z: 0.0,
+                    z: RenderLayer::BackgroundParallax.z(0.0),
                     ..Default::default()
                 },
                 LayerData {
@@ -28,7 +31,7 @@ impl Plugin for CustomParallaxPlugin {
                     cols: 1,
                     rows: 1,
                     scale: 4.5,
-                    z: 1.0,
+                    z: RenderLayer::BackgroundParallax.z(1.0),
                     ..Default::default()
                 },
                 LayerData {
@@ -39,7 +42,7 @@ impl Plugin for CustomParallaxPlugin {
                     cols: 1,
                     rows: 1,
                     scale: 4.5,
-                    z: 2.0,
+                    z: RenderLayer::BackgroundParallax.z(2.0),
                     ..Default::default()
                 },
                 LayerData {
@@ -50,7 +53,7 @@ impl Plugin for CustomParallaxPlugin {
                     cols: 1,
                     rows: 1,
                     scale: 4.5,
-                    z: 3.0,
+                    z: RenderLayer::BackgroundParallax.z(3.0),
                     ..Default::default()
                 },
                 LayerData {
@@ -61,76 +64,75 @@ impl Plugin for CustomParallaxPlugin {
                     cols: 1,
                     rows: 1,
                     scale: 4.5,
-                    z: 4.0,
+                    z: RenderLayer::BackgroundParallax.z(4.0),
                     ..Default::default()
                 },
                 LayerData {
                     speed: 0.3,
                     speed_y: 1.0,
-                    path: "parallax-cube/parallax-cube-1.png".to_string(),
+                    path: "parallax-cube/Parallax-Cube-1.png".to_string(),
                     tile_size: Vec2::new(128.0, 128.0),
                     cols: 2,
                     rows: 3,
                     scale: 3.0,
-                    z: 4.1,
+                    z: RenderLayer::BackgroundParallax.z(4.1),
                     ..Default::default()
                 },
                 LayerData {
                     speed: 0.32,
                     speed_y: 1.0,
-                    path: "parallax-cube/parallax-cube-2.png".to_string(),
+                    path: "parallax-cube/Parallax-Cube-2.png".to_string(),
                     tile_size: Vec2::new(128.0, 128.0),
                     cols: 2,
                     rows: 3,
                     scale: 3.0,
-                    z: 4.2,
+                    z: RenderLayer::BackgroundParallax.z(4.2),
                     ..Default::default()
                 },
                 LayerData {
                     speed: 0.34,
                     speed_y: 1.0,
-                    path: "parallax-cube/parallax-cube-3.png".to_string(),
+                    path: "parallax-cube/Parallax-Cube-3.png".to_string(),
                     tile_size: Vec2::new(128.0, 128.0),
                     cols: 2,
                     rows: 3,
                     scale: 3.0,
-                    z: 4.3,
+                    z: RenderLayer::BackgroundParallax.z(4.3),
                     ..Default::default()
                 },
                 LayerData {
                     speed: 0.36,
                     speed_y: 1.0,
-                    path: "parallax-cube/parallax-cube-4.png".to_string(),
+                    path: "parallax-cube/Parallax-Cube-4.png".to_string(),
                     tile_size: Vec2::new(128.0, 128.0),
                     cols: 2,
                     rows: 3,
                     scale: 3.0,
-                    z: 4.4,
+                    z: RenderLayer::BackgroundParallax.z(4.4),
                     ..Default::default()
                 },
                 LayerData {
                     speed: 0.38,
                     speed_y: 1.0,
-                    path: "parallax-cube/parallax-cube-5.png".to_string(),
+                    path: "parallax-cube/Parallax-Cube-5.png".to_string(),
                     tile_size: Vec2::new(128.0, 128.0),
                     cols: 2,
                     rows: 3,
                     scale: 3.0,
-                    z: 4.5,
+                    z: RenderLayer::BackgroundParallax.z(4.5),
                     ..Default::default()
                 },
                 LayerData {
                     speed: 0.4,
                     speed_y: 1.0,
-                    path: "parallax-cube/parallax-cube-6.png".to_string(),
+                    path: "parallax-cube/Parallax-Cube-6.png".to_string(),
                     tile_size: Vec2::new(128.0, 128.0),
                     cols: 2,
                     rows: 3,
                     scale: 3.0,
-                    z: 4.6,
+                    z: RenderLayer::BackgroundParallax.z(4.6),
                     ..Default::default()
                 },
-                
             ],
             ..Default::default()
         };
@@ -138,7 +140,49 @@ impl Plugin for CustomParallaxPlugin {
         app.insert_resource(parallax)
             .add_plugin(ParallaxPlugin)
             .add_startup_system(initialize_camera_system)
-            .add_system(move_camera_system);
+            .add_system(move_camera_system)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                apply_layer_parallax.before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/* Parallax of LDtk layers, which follow `factor` of the parallax camera's movement away
+from the level's centre, as in the editor. With `scaling`, a zoomed out camera also
+scales the layer up by its factor so far layers keep their size; at the default zoom
+this does nothing.
+ */
+#[derive(Component)]
+pub struct LayerParallax {
+    pub factor: Vec2,
+    pub scaling: bool,
+    // level centre in the world, and in the layer's parent
+    pub center: Vec2,
+    pub pivot: Vec2,
+    // translation without parallax
+    pub base: Vec3,
+}
+
+pub fn apply_layer_parallax(
+    cameras: Query<(&Transform, &OrthographicProjection), With<ParallaxCameraComponent>>,
+    mut layers: Query<(&LayerParallax, &mut Transform), Without<ParallaxCameraComponent>>,
+) {
+    let (camera, projection) = match cameras.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    for (parallax, mut transform) in layers.iter_mut() {
+        let scale = if parallax.scaling {
+            Vec2::ONE + (projection.scale - 1.0) * parallax.factor
+        } else {
+            Vec2::ONE
+        };
+        let scroll = (camera.translation.truncate() - parallax.center) * parallax.factor;
+        let translation =
+            parallax.pivot + (parallax.base.truncate() - parallax.pivot) * scale + scroll;
+        transform.translation = translation.extend(parallax.base.z);
+        transform.scale = scale.extend(1.0);
     }
 }
 
@@ -184,3 +228,62 @@ fn move_camera_system(
         camera_move_speed_y: movement.y,
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(camera: Vec2, zoom: f32, parallax: LayerParallax) -> Transform {
+        let mut world = World::new();
+        world
+            .spawn()
+            .insert(Transform::from_translation(camera.extend(999.0)))
+            .insert(OrthographicProjection {
+                scale: zoom,
+                ..Default::default()
+            })
+            .insert(ParallaxCameraComponent);
+        let layer = world
+            .spawn()
+            .insert(parallax)
+            .insert(Transform::default())
+            .id();
+        let mut stage = SystemStage::single_threaded().with_system(apply_layer_parallax);
+        stage.run(&mut world);
+        *world.get::<Transform>(layer).unwrap()
+    }
+
+    fn layer(scaling: bool) -> LayerParallax {
+        LayerParallax {
+            factor: Vec2::new(0.5, 0.25),
+            scaling,
+            center: Vec2::new(400.0, -300.0),
+            pivot: Vec2::new(400.0, -300.0),
+            base: Vec3::new(8.0, -8.0, 3.0),
+        }
+    }
+
+    #[test]
+    fn layers_follow_their_factor_of_the_camera() {
+        // at the level's centre, layers are where LDtk draws them
+        let transform = run(Vec2::new(400.0, -300.0), 1.0, layer(false));
+        assert_eq!(transform.translation, Vec3::new(8.0, -8.0, 3.0));
+
+        let transform = run(Vec2::new(500.0, -260.0), 1.0, layer(false));
+        assert_eq!(transform.translation, Vec3::new(58.0, 2.0, 3.0));
+        assert_eq!(transform.scale, Vec3::ONE);
+    }
+
+    #[test]
+    fn scaling_layers_grow_around_the_pivot_when_zoomed_out() {
+        let transform = run(Vec2::new(400.0, -300.0), 1.0, layer(true));
+        assert_eq!(transform.scale, Vec3::ONE);
+
+        let transform = run(Vec2::new(400.0, -300.0), 3.0, layer(true));
+        assert_eq!(transform.scale, Vec3::new(2.0, 1.5, 1.0));
+        let base = Vec2::new(8.0, -8.0);
+        let pivot = Vec2::new(400.0, -300.0);
+        let expected = pivot + (base - pivot) * Vec2::new(2.0, 1.5);
+        assert_eq!(transform.translation, expected.extend(3.0));
+    }
+}
@@ -0,0 +1,293 @@
+use bevy::prelude::*;
+use bevy_parallax::{ParallaxCameraComponent, ParallaxMoveEvent};
+
+use crate::animated_sprite::KeyBinds;
+use crate::character_controller::{CharacterController, ControllerSystem};
+use crate::collision::Collider;
+use crate::ldtk::LdtkProject;
+use crate::ldtk_fields::{EntityRef, FromFields};
+use crate::level_entities::{
+    instance_position, EntityBounds, LevelEntity, LevelFields, RegisterLdtkEntityExt,
+};
+use crate::level_streaming::{LevelStates, LevelWorld};
+use crate::render_layers::{RenderLayer, LAYER_DEPTH};
+use crate::simulation::Position;
+use crate::{ldtk_component, ldtk_enum, Player};
+
+// seconds for each half of the fade
+const FADE_TIME: f32 = 0.4;
+
+pub struct DoorsPlugin;
+
+impl Plugin for DoorsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_ldtk_component::<Door>("Door")
+            .add_ldtk_component::<Entry>("Entry")
+            .add_startup_system(spawn_fade_overlay)
+            .add_system(enter_doors.after(ControllerSystem::Input))
+            .add_system(run_transition.after(ControllerSystem::Input));
+    }
+}
+
+/* Doors and exits, placed in LDtk as "Door" entities whose `Target` field references
+an "Entry" entity, usually in another level. Walking into a door (or pressing the
+interact key inside it, for doors with `Interact` set) fades the screen out, moves
+the camera so the target level streams in, puts the player on the entry and fades
+back in. Doors with `Locked` set stay shut until something opens them, e.g. a trigger
+(see `triggers`); opened doors are remembered in their level's `LevelState`.
+ */
+ldtk_component! {
+    #[derive(Component)]
+    pub struct Door {
+        pub target: EntityRef,
+        // only opened with the interact key
+        pub interact: Option<bool>,
+        pub locked: Option<bool>,
+    }
+}
+
+ldtk_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Facing {
+        Left,
+        Right,
+    }
+}
+
+ldtk_component! {
+    /// Where doors leading here put the player: standing on the bottom of the entity.
+    #[derive(Component)]
+    pub struct Entry {
+        pub facing: Option<Facing>,
+    }
+}
+
+/// Door transition in progress.
+pub struct LevelTransition {
+    pub phase: TransitionPhase,
+    pub level: String,
+    // where the player's feet go
+    pub entry: Vec2,
+    pub facing: Option<Facing>,
+    // 0.0 clear to 1.0 black
+    pub fade: f32,
+    // load the level again from its saved state once the screen is black
+    pub reload: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionPhase {
+    FadeOut,
+    // until the target level and its entities are there
+    Loading,
+    FadeIn,
+}
+
+#[derive(Component)]
+pub struct FadeOverlay;
+
+fn spawn_fade_overlay(mut commands: Commands) {
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: Color::rgba(0.0, 0.0, 0.0, 0.0),
+                // covers the view wherever the camera is
+                custom_size: Some(Vec2::splat(10000.0)),
+                ..Default::default()
+            },
+            transform: Transform::from_xyz(0.0, 0.0, RenderLayer::Ui.z(LAYER_DEPTH - 2.0)),
+            ..Default::default()
+        })
+        .insert(FadeOverlay);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn enter_doors(
+    mut commands: Commands,
+    input: Res<Input<KeyCode>>,
+    keybinds: Res<KeyBinds>,
+    projects: Res<Assets<LdtkProject>>,
+    states: Res<LevelStates>,
+    world: Option<Res<LevelWorld>>,
+    transition: Option<Res<LevelTransition>>,
+    players: Query<(&Position, &Collider), With<Player>>,
+    doors: Query<(&Door, &EntityBounds, &LevelEntity)>,
+    // set after going through a door, until the player has stepped out of all doors, so
+    // arriving on a door does not send them straight back
+    mut blocked: Local<bool>,
+) {
+    if transition.is_some() {
+        return;
+    }
+    let (project, world) =
+        match world.and_then(|world| Some((projects.get(&world.project)?, world))) {
+            Some(loaded) => loaded,
+            None => return,
+        };
+    let (position, collider) = match players.get_single() {
+        Ok(player) => player,
+        Err(_) => return,
+    };
+
+    let mut inside = doors
+        .iter()
+        .filter(|(_, bounds, _)| bounds.overlaps(position.current, collider.half_size))
+        .filter(|(door, _, door_entity)| {
+            !door.locked.unwrap_or(false) || is_open(&states, door_entity)
+        })
+        .peekable();
+    if inside.peek().is_none() {
+        *blocked = false;
+        return;
+    }
+    let (door, _, door_entity) = match inside.find(|(door, _, _)| {
+        if door.interact.unwrap_or(false) {
+            input.just_pressed(keybinds.interact)
+        } else {
+            !*blocked
+        }
+    }) {
+        Some(door) => door,
+        None => return,
+    };
+
+    // the entry is read from the project, its level may not be loaded yet
+    let target = project
+        .level_by_iid(&door.target.level_iid)
+        .and_then(|level| Some((level, world.origin(level)?)))
+        .and_then(|(level, origin)| {
+            level.layers().iter().find_map(|layer| {
+                let instance = layer
+                    .entity_instances
+                    .iter()
+                    .find(|instance| instance.iid == door.target.entity_iid)?;
+                Some((level, origin, layer, instance))
+            })
+        });
+    let (level, origin, layer, instance) = match target {
+        Some(target) => target,
+        None => {
+            warn!(
+                "{}: door {} leads to an entity that does not exist",
+                door_entity.level, door_entity.iid
+            );
+            *blocked = true;
+            return;
+        }
+    };
+    let pivot = Vec2::new(instance.pivot[0], instance.pivot[1]);
+    let size = Vec2::new(instance.width as f32, instance.height as f32);
+    let bottom_center = instance_position(origin, layer, instance)
+        + (Vec2::new(0.5, 1.0) - pivot) * size * Vec2::new(1.0, -1.0);
+    let fields: Vec<_> = instance
+        .field_instances
+        .iter()
+        .filter_map(|field| Some((field.identifier.clone(), field.typed_value().ok()?)))
+        .collect();
+
+    *blocked = true;
+    commands.insert_resource(LevelTransition {
+        phase: TransitionPhase::FadeOut,
+        level: level.identifier.clone(),
+        entry: bottom_center,
+        facing: Entry::from_fields(&fields)
+            .ok()
+            .and_then(|entry| entry.facing),
+        fade: 0.0,
+        reload: false,
+    });
+}
+
+/// Whether a locked door has been opened.
+pub fn is_open(states: &LevelStates, door: &LevelEntity) -> bool {
+    states
+        .get(&door.level)
+        .map_or(false, |state| state.flags.contains(&door.iid))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_transition(
+    mut commands: Commands,
+    time: Res<Time>,
+    world: Option<ResMut<LevelWorld>>,
+    transition: Option<ResMut<LevelTransition>>,
+    mut move_events: EventWriter<ParallaxMoveEvent>,
+    cameras: Query<&Transform, With<ParallaxCameraComponent>>,
+    mut overlays: Query<
+        (&mut Sprite, &mut Transform),
+        (With<FadeOverlay>, Without<ParallaxCameraComponent>),
+    >,
+    mut players: Query<
+        (
+            &mut Position,
+            &Collider,
+            &mut CharacterController,
+            &mut TextureAtlasSprite,
+        ),
+        With<Player>,
+    >,
+    roots: Query<(), With<LevelFields>>,
+) {
+    let mut transition = match transition {
+        Some(transition) => transition,
+        None => return,
+    };
+    let camera = cameras
+        .get_single()
+        .map_or(Vec2::ZERO, |camera| camera.translation.truncate());
+
+    // the player stands still while the screen is dark
+    for (_, _, mut controller, _) in players.iter_mut() {
+        controller.move_direction = 0.0;
+        controller.vertical_input = 0.0;
+        controller.jump_requested = false;
+        controller.dash_requested = false;
+    }
+
+    let step = time.delta_seconds() / FADE_TIME;
+    match transition.phase {
+        TransitionPhase::FadeOut => {
+            transition.fade = (transition.fade + step).min(1.0);
+            if transition.fade >= 1.0 {
+                if let (true, Some(mut world)) = (transition.reload, world) {
+                    world.reload(&transition.level);
+                }
+                // through bevy_parallax, so the backgrounds follow
+                let delta = transition.entry - camera;
+                move_events.send(ParallaxMoveEvent {
+                    camera_move_speed: delta.x,
+                    camera_move_speed_y: delta.y,
+                });
+                transition.phase = TransitionPhase::Loading;
+            }
+        }
+        TransitionPhase::Loading => {
+            let loaded = world
+                .and_then(|world| world.root(&transition.level))
+                .map_or(false, |root| roots.get(root).is_ok());
+            if loaded {
+                for (mut position, collider, mut controller, mut sprite) in players.iter_mut() {
+                    position.teleport(transition.entry + Vec2::new(0.0, collider.half_size.y));
+                    position.velocity = Vec2::ZERO;
+                    if let Some(facing) = transition.facing {
+                        controller.facing = if facing == Facing::Left { -1.0 } else { 1.0 };
+                        sprite.flip_x = facing == Facing::Left;
+                    }
+                }
+                transition.phase = TransitionPhase::FadeIn;
+            }
+        }
+        TransitionPhase::FadeIn => {
+            transition.fade = (transition.fade - step).max(0.0);
+            if transition.fade <= 0.0 {
+                commands.remove_resource::<LevelTransition>();
+            }
+        }
+    }
+
+    for (mut sprite, mut transform) in overlays.iter_mut() {
+        sprite.color.set_a(transition.fade);
+        transform.translation.x = camera.x;
+        transform.translation.y = camera.y;
+    }
+}
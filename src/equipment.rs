@@ -0,0 +1,202 @@
+use bevy::prelude::*;
+use bevy::sprite::Anchor;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+
+use crate::render_layers::Y_SORT_STEP;
+use crate::slices::{apply_frame_anchors, FrameSlices};
+use crate::EntityAnimations;
+
+pub struct EquipmentPlugin;
+
+impl Plugin for EquipmentPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(update_equipment_layers).add_system_to_stage(
+            CoreStage::PostUpdate,
+            sync_equipment_layers.after(apply_frame_anchors),
+        );
+    }
+}
+
+/* Paper-doll layering: the entity's own sprite is the body, and each equipped item is a
+child sprite drawn from its own sheet. Item sheets use the body's frame layout, so the
+children just copy the body's frame every update and stay on the same timeline. When
+the body's frames have a `hand` slice, the weapon follows it instead: weapon sheets are
+then drawn with the grip at the centre of each frame.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum EquipmentSlot {
+    Armor,
+    Hair,
+    Weapon,
+}
+
+impl EquipmentSlot {
+    // drawn in front of the body, in this order, without reaching y-sorted neighbours
+    fn z_offset(&self) -> f32 {
+        match self {
+            EquipmentSlot::Armor => Y_SORT_STEP * 0.25,
+            EquipmentSlot::Hair => Y_SORT_STEP * 0.5,
+            EquipmentSlot::Weapon => Y_SORT_STEP * 0.75,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum Grip {
+    OneHanded,
+    TwoHanded,
+}
+
+impl Default for Grip {
+    fn default() -> Self {
+        Grip::OneHanded
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct EquipmentItem {
+    pub slot: EquipmentSlot,
+    // sheet with the same frame layout as the body's
+    pub sheet: String,
+    // weapons only; picks the matching animation variants
+    #[serde(default)]
+    pub grip: Option<Grip>,
+}
+
+#[derive(Component, Default)]
+pub struct Equipment {
+    items: HashMap<EquipmentSlot, EquipmentItem>,
+}
+
+impl Equipment {
+    pub fn with(items: &[EquipmentItem]) -> Self {
+        let mut equipment = Equipment::default();
+        for item in items {
+            equipment.equip(item.clone());
+        }
+        equipment
+    }
+
+    /// Puts `item` in its slot, returning what was there before.
+    pub fn equip(&mut self, item: EquipmentItem) -> Option<EquipmentItem> {
+        self.items.insert(item.slot, item)
+    }
+
+    pub fn unequip(&mut self, slot: EquipmentSlot) -> Option<EquipmentItem> {
+        self.items.remove(&slot)
+    }
+
+    pub fn get(&self, slot: EquipmentSlot) -> Option<&EquipmentItem> {
+        self.items.get(&slot)
+    }
+
+    pub fn grip(&self) -> Grip {
+        self.get(EquipmentSlot::Weapon)
+            .and_then(|weapon| weapon.grip)
+            .unwrap_or_default()
+    }
+}
+
+/// Child sprite showing the item in `slot`.
+#[derive(Component)]
+pub struct EquipmentLayer {
+    pub slot: EquipmentSlot,
+    pub sheet: String,
+}
+
+fn update_equipment_layers(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    mut query: Query<
+        (
+            Entity,
+            &Equipment,
+            &Handle<TextureAtlas>,
+            Option<&Children>,
+            Option<&mut EntityAnimations>,
+        ),
+        Changed<Equipment>,
+    >,
+    layers: Query<&EquipmentLayer>,
+) {
+    for (entity, equipment, body_atlas, children, animations) in query.iter_mut() {
+        if let Some(mut animations) = animations {
+            animations.set_grip(equipment.grip());
+        }
+
+        let mut current: Vec<EquipmentSlot> = Vec::new();
+        for child in children.iter().flat_map(|children| children.iter()) {
+            let layer = match layers.get(*child) {
+                Ok(layer) => layer,
+                Err(_) => continue,
+            };
+            match equipment.get(layer.slot) {
+                Some(item) if item.sheet == layer.sheet => current.push(layer.slot),
+                _ => commands.entity(*child).despawn_recursive(),
+            }
+        }
+
+        let layout = match texture_atlases.get(body_atlas) {
+            Some(layout) => layout,
+            None => continue,
+        };
+        let (size, textures) = (layout.size, layout.textures.clone());
+        for item in equipment.items.values() {
+            if current.contains(&item.slot) {
+                continue;
+            }
+            let texture_atlas = texture_atlases.add(TextureAtlas {
+                texture: asset_server.load(&item.sheet),
+                size,
+                textures: textures.clone(),
+                texture_handles: None,
+            });
+            let layer = commands
+                .spawn_bundle(SpriteSheetBundle {
+                    texture_atlas,
+                    transform: Transform::from_xyz(0.0, 0.0, item.slot.z_offset()),
+                    ..Default::default()
+                })
+                .insert(EquipmentLayer {
+                    slot: item.slot,
+                    sheet: item.sheet.clone(),
+                })
+                .id();
+            commands.entity(entity).add_child(layer);
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn sync_equipment_layers(
+    bodies: Query<(&TextureAtlasSprite, &Children, Option<&FrameSlices>), With<Equipment>>,
+    mut layers: Query<
+        (&EquipmentLayer, &mut TextureAtlasSprite, &mut Transform),
+        Without<Equipment>,
+    >,
+) {
+    for (body, children, slices) in bodies.iter() {
+        let hand = slices.and_then(|slices| slices.point("hand", body));
+        for child in children.iter() {
+            if let Ok((layer, mut sprite, mut transform)) = layers.get_mut(*child) {
+                sprite.index = body.index;
+                sprite.flip_x = body.flip_x;
+                sprite.flip_y = body.flip_y;
+                match hand.filter(|_| layer.slot == EquipmentSlot::Weapon) {
+                    Some(hand) => {
+                        sprite.anchor = Anchor::Center;
+                        transform.translation.x = hand.x;
+                        transform.translation.y = hand.y;
+                    }
+                    None => {
+                        sprite.anchor = body.anchor.clone();
+                        transform.translation.x = 0.0;
+                        transform.translation.y = 0.0;
+                    }
+                }
+            }
+        }
+    }
+}
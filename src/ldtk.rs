@@ -0,0 +1,394 @@
+use std::path::{Component, Path, PathBuf};
+
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use serde::Deserialize;
+
+use crate::asset_check::AssetManifest;
+use crate::ldtk_fields::{FieldError, FieldValue};
+
+pub const LEVEL_PROJECT: &str = "Levels/basic.ldtk";
+
+pub struct LdtkPlugin;
+
+impl Plugin for LdtkPlugin {
+    fn build(&self, app: &mut App) {
+        app.world
+            .get_resource_or_insert_with(AssetManifest::default)
+            .add("level project", LEVEL_PROJECT);
+        app.add_asset::<LdtkProject>()
+            .init_asset_loader::<LdtkLoader>();
+    }
+}
+
+/* The parts of an LDtk project (https://ldtk.io/json) the game uses. Fields LDtk
+computes for convenience start with `__`; paths in the file are relative to it and
+are resolved to asset paths on load.
+ */
+#[derive(Deserialize, TypeUuid)]
+#[serde(rename_all = "camelCase")]
+#[uuid = "3f7b2d1e-9c4a-4e8f-a6b5-0d2c8e1f7a93"]
+pub struct LdtkProject {
+    pub bg_color: String,
+    pub defs: Definitions,
+    pub levels: Vec<Level>,
+}
+
+impl LdtkProject {
+    pub fn level(&self, identifier: &str) -> Option<&Level> {
+        self.levels
+            .iter()
+            .find(|level| level.identifier == identifier)
+    }
+
+    pub fn level_by_iid(&self, iid: &str) -> Option<&Level> {
+        self.levels.iter().find(|level| level.iid == iid)
+    }
+
+    pub fn tileset(&self, uid: i32) -> Option<&TilesetDef> {
+        self.defs.tilesets.iter().find(|tileset| tileset.uid == uid)
+    }
+
+    pub fn layer_def(&self, uid: i32) -> Option<&LayerDef> {
+        self.defs.layers.iter().find(|layer| layer.uid == uid)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Definitions {
+    pub layers: Vec<LayerDef>,
+    pub tilesets: Vec<TilesetDef>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerDef {
+    pub uid: i32,
+    pub identifier: String,
+    #[serde(rename = "type")]
+    pub layer_type: String,
+    pub grid_size: i32,
+    // fraction of the camera's movement the layer follows; 0 scrolls with the level
+    #[serde(default)]
+    pub parallax_factor_x: f32,
+    #[serde(default)]
+    pub parallax_factor_y: f32,
+    #[serde(default = "default_parallax_scaling")]
+    pub parallax_scaling: bool,
+    #[serde(default)]
+    pub int_grid_values: Vec<IntGridValueDef>,
+    // IntGrid layer the rules read, for auto-layers without their own IntGrid
+    #[serde(default)]
+    pub auto_source_layer_def_uid: Option<i32>,
+    #[serde(default)]
+    pub auto_rule_groups: Vec<AutoRuleGroup>,
+}
+
+fn default_parallax_scaling() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct IntGridValueDef {
+    pub value: i32,
+    pub identifier: Option<String>,
+    pub color: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoRuleGroup {
+    pub uid: i32,
+    pub name: String,
+    pub active: bool,
+    // only applied where a layer instance enables it
+    #[serde(default)]
+    pub is_optional: bool,
+    pub rules: Vec<AutoRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoRule {
+    pub uid: i32,
+    pub active: bool,
+    // pattern width and height, always odd
+    pub size: i32,
+    pub tile_ids: Vec<i32>,
+    pub chance: f32,
+    pub break_on_match: bool,
+    pub pattern: Vec<i32>,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    pub x_modulo: i32,
+    pub y_modulo: i32,
+    #[serde(default)]
+    pub x_offset: i32,
+    #[serde(default)]
+    pub y_offset: i32,
+    #[serde(default)]
+    pub checker: Checker,
+    pub tile_mode: TileMode,
+    #[serde(default)]
+    pub pivot_x: f32,
+    #[serde(default)]
+    pub pivot_y: f32,
+    #[serde(default)]
+    pub out_of_bounds_value: Option<i32>,
+    #[serde(default)]
+    pub perlin_active: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Checker {
+    None,
+    Horizontal,
+    Vertical,
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Checker::None
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TileMode {
+    Single,
+    Stamp,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TilesetDef {
+    pub uid: i32,
+    pub identifier: String,
+    pub rel_path: Option<String>,
+    // asset path of `rel_path`, set by the loader
+    #[serde(skip)]
+    pub path: Option<String>,
+    pub px_wid: i32,
+    pub px_hei: i32,
+    pub tile_grid_size: i32,
+    pub spacing: i32,
+    pub padding: i32,
+    #[serde(default)]
+    pub custom_data: Vec<TileCustomData>,
+}
+
+impl TilesetDef {
+    pub fn columns(&self) -> i32 {
+        ((self.px_wid - self.padding * 2 + self.spacing) / (self.tile_grid_size + self.spacing))
+            .max(1)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TileCustomData {
+    pub tile_id: i32,
+    pub data: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Level {
+    pub identifier: String,
+    pub iid: String,
+    pub uid: i32,
+    pub world_x: i32,
+    pub world_y: i32,
+    pub px_wid: i32,
+    pub px_hei: i32,
+    // the level's own colour, or the project's default
+    #[serde(rename = "__bgColor")]
+    pub bg_color: String,
+    // only missing with "save levels separately", which the game does not support
+    #[serde(default)]
+    pub layer_instances: Option<Vec<LayerInstance>>,
+    #[serde(default)]
+    pub field_instances: Vec<FieldInstance>,
+    // levels touching this one in the world
+    #[serde(rename = "__neighbours", default)]
+    pub neighbours: Vec<Neighbour>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Neighbour {
+    pub level_iid: String,
+    // "n", "s", "w" or "e"
+    pub dir: String,
+}
+
+impl Level {
+    pub fn layers(&self) -> &[LayerInstance] {
+        self.layer_instances.as_deref().unwrap_or_default()
+    }
+
+    /// The first IntGrid layer, which the game uses for collision.
+    pub fn int_grid(&self) -> Option<&LayerInstance> {
+        self.layers()
+            .iter()
+            .find(|layer| layer.layer_type == "IntGrid")
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerInstance {
+    #[serde(rename = "__identifier")]
+    pub identifier: String,
+    #[serde(rename = "__type")]
+    pub layer_type: String,
+    #[serde(rename = "__cWid")]
+    pub c_wid: i32,
+    #[serde(rename = "__cHei")]
+    pub c_hei: i32,
+    #[serde(rename = "__gridSize")]
+    pub grid_size: i32,
+    #[serde(rename = "__opacity")]
+    pub opacity: f32,
+    #[serde(rename = "__pxTotalOffsetX")]
+    pub px_total_offset_x: i32,
+    #[serde(rename = "__pxTotalOffsetY")]
+    pub px_total_offset_y: i32,
+    #[serde(rename = "__tilesetDefUid")]
+    pub tileset_def_uid: Option<i32>,
+    pub layer_def_uid: i32,
+    pub visible: bool,
+    #[serde(default)]
+    pub seed: i64,
+    // uids of the optional rule groups enabled on this instance
+    #[serde(default)]
+    pub optional_rules: Vec<i32>,
+    #[serde(default)]
+    pub int_grid_csv: Vec<i32>,
+    #[serde(default)]
+    pub auto_layer_tiles: Vec<TileInstance>,
+    #[serde(default)]
+    pub grid_tiles: Vec<TileInstance>,
+    #[serde(default)]
+    pub entity_instances: Vec<EntityInstance>,
+}
+
+impl LayerInstance {
+    /// Painted tiles for tile layers, rule-generated ones for auto-layers.
+    pub fn tiles(&self) -> &[TileInstance] {
+        if self.grid_tiles.is_empty() {
+            &self.auto_layer_tiles
+        } else {
+            &self.grid_tiles
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+pub struct TileInstance {
+    // position in the layer, in pixels
+    pub px: [i32; 2],
+    // position in the tileset image
+    pub src: [i32; 2],
+    // bit 0: flip x, bit 1: flip y
+    pub f: u8,
+    // tile id in the tileset
+    pub t: i32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntityInstance {
+    #[serde(rename = "__identifier")]
+    pub identifier: String,
+    pub iid: String,
+    pub def_uid: i32,
+    // position of the pivot in the layer, in pixels
+    pub px: [i32; 2],
+    // fraction of the size, from the top-left corner
+    #[serde(rename = "__pivot")]
+    pub pivot: [f32; 2],
+    pub width: i32,
+    pub height: i32,
+    #[serde(default)]
+    pub field_instances: Vec<FieldInstance>,
+}
+
+/// A custom field of an entity or level, with its value as LDtk wrote it.
+#[derive(Deserialize)]
+pub struct FieldInstance {
+    #[serde(rename = "__identifier")]
+    pub identifier: String,
+    // e.g. "Int", "LocalEnum.Item" or "Array<Point>"
+    #[serde(rename = "__type")]
+    pub field_type: String,
+    #[serde(rename = "__value")]
+    pub value: serde_json::Value,
+}
+
+impl FieldInstance {
+    pub fn typed_value(&self) -> Result<FieldValue, FieldError> {
+        FieldValue::parse(&self.field_type, &self.value).map_err(|problem| FieldError {
+            field: self.identifier.clone(),
+            problem,
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct LdtkLoader;
+
+impl AssetLoader for LdtkLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut project: LdtkProject = serde_json::from_slice(bytes)?;
+            let directory = load_context
+                .path()
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            for tileset in project.defs.tilesets.iter_mut() {
+                tileset.path = tileset
+                    .rel_path
+                    .as_ref()
+                    .map(|rel_path| resolve_relative(&directory, rel_path));
+            }
+            load_context.set_default_asset(LoadedAsset::new(project));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ldtk"]
+    }
+}
+
+// joins without touching the filesystem, so `..` works on asset paths
+pub fn resolve_relative(directory: &Path, relative: &str) -> String {
+    let mut path = PathBuf::new();
+    for component in directory.join(relative).components() {
+        match component {
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::Normal(part) => path.push(part),
+            _ => {}
+        }
+    }
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Parses LDtk's `#rrggbb` colors.
+pub fn parse_color(hex: &str) -> Color {
+    let hex = hex.trim_start_matches('#');
+    let channel = |index: usize| {
+        hex.get(index..index + 2)
+            .and_then(|value| u8::from_str_radix(value, 16).ok())
+            .unwrap_or(0)
+    };
+    Color::rgb_u8(channel(0), channel(2), channel(4))
+}
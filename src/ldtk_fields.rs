@@ -0,0 +1,661 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::ldtk::parse_color;
+
+/* Values of LDtk custom fields, typed from the `__type` LDtk writes next to them.
+Enum values keep the enum's name so fields of different enums can be told apart.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    // left empty in the editor
+    Null,
+    Int(i64),
+    Float(f32),
+    Bool(bool),
+    // also multiline text and file paths
+    String(String),
+    Color(Color),
+    Enum { enum_name: String, value: String },
+    // grid cell in the level
+    Point(IVec2),
+    EntityRef(EntityRef),
+    Array(Vec<FieldValue>),
+}
+
+/// Another entity instance, possibly in another level.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntityRef {
+    pub entity_iid: String,
+    pub layer_iid: String,
+    pub level_iid: String,
+    #[serde(default)]
+    pub world_iid: Option<String>,
+}
+
+impl FieldValue {
+    pub fn parse(field_type: &str, value: &Value) -> Result<FieldValue, FieldProblem> {
+        if value.is_null() {
+            return Ok(FieldValue::Null);
+        }
+        let invalid = || FieldProblem::InvalidValue {
+            field_type: field_type.to_string(),
+            value: value.to_string(),
+        };
+        if let Some(item_type) = field_type
+            .strip_prefix("Array<")
+            .and_then(|rest| rest.strip_suffix('>'))
+        {
+            return value
+                .as_array()
+                .ok_or_else(invalid)?
+                .iter()
+                .map(|item| FieldValue::parse(item_type, item))
+                .collect::<Result<_, _>>()
+                .map(FieldValue::Array);
+        }
+
+        let parsed = match field_type {
+            "Int" => value.as_i64().map(FieldValue::Int),
+            "Float" => value.as_f64().map(|float| FieldValue::Float(float as f32)),
+            "Bool" => value.as_bool().map(FieldValue::Bool),
+            "String" | "Multilines" | "FilePath" => value
+                .as_str()
+                .map(|string| FieldValue::String(string.to_string())),
+            "Color" => value
+                .as_str()
+                .filter(|color| color.starts_with('#'))
+                .map(|color| FieldValue::Color(parse_color(color))),
+            "Point" => match (
+                value.get("cx").and_then(Value::as_i64),
+                value.get("cy").and_then(Value::as_i64),
+            ) {
+                (Some(x), Some(y)) => Some(FieldValue::Point(IVec2::new(x as i32, y as i32))),
+                _ => None,
+            },
+            "EntityRef" => serde_json::from_value(value.clone())
+                .ok()
+                .map(FieldValue::EntityRef),
+            _ => {
+                let enum_name = field_type
+                    .strip_prefix("LocalEnum.")
+                    .or_else(|| field_type.strip_prefix("ExternalEnum."))
+                    .ok_or_else(|| FieldProblem::UnsupportedType(field_type.to_string()))?;
+                value.as_str().map(|name| FieldValue::Enum {
+                    enum_name: enum_name.to_string(),
+                    value: name.to_string(),
+                })
+            }
+        };
+        parsed.ok_or_else(invalid)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            FieldValue::Null => "nothing",
+            FieldValue::Int(_) => "Int",
+            FieldValue::Float(_) => "Float",
+            FieldValue::Bool(_) => "Bool",
+            FieldValue::String(_) => "String",
+            FieldValue::Color(_) => "Color",
+            FieldValue::Enum { .. } => "Enum",
+            FieldValue::Point(_) => "Point",
+            FieldValue::EntityRef(_) => "EntityRef",
+            FieldValue::Array(_) => "Array",
+        }
+    }
+}
+
+/* Mapping fields onto Rust types. `ldtk_component!` implements `FromFields` for a
+struct, reading each Rust field from the LDtk field of the same name (ignoring case,
+so `max_speed` reads "Max_speed"):
+
+    ldtk_component! {
+        #[derive(Component)]
+        pub struct Patrol {
+            pub speed: f32,
+            pub path: Vec<IVec2>,
+            pub partner: Option<LinkedEntity>,
+        }
+    }
+
+Missing or empty fields are only accepted for `Option`s. `ldtk_enum!` does the same
+for Rust enums matching an LDtk enum's values.
+ */
+pub trait FromFieldValue: Sized {
+    // `None` when the instance has no such field
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem>;
+
+    /// Entity references inside the value, to be linked once their entities exist.
+    fn collect_links<'a>(&'a mut self, _links: &mut Vec<&'a mut LinkedEntity>) {}
+}
+
+pub trait FromFields: Sized {
+    fn from_fields(fields: &[(String, FieldValue)]) -> Result<Self, FieldError>;
+
+    fn collect_links<'a>(&'a mut self, _links: &mut Vec<&'a mut LinkedEntity>) {}
+}
+
+/// Reads the field named `name`, ignoring case.
+pub fn field<T: FromFieldValue>(
+    fields: &[(String, FieldValue)],
+    name: &str,
+) -> Result<T, FieldError> {
+    let value = fields
+        .iter()
+        .find(|(identifier, _)| identifier.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value);
+    T::from_field_value(value).map_err(|problem| FieldError {
+        field: name.to_string(),
+        problem,
+    })
+}
+
+// the value of a required field
+fn required(value: Option<&FieldValue>) -> Result<&FieldValue, FieldProblem> {
+    match value {
+        None => Err(FieldProblem::Missing),
+        Some(FieldValue::Null) => Err(FieldProblem::Empty),
+        Some(value) => Ok(value),
+    }
+}
+
+fn mismatch(expected: &'static str, found: &FieldValue) -> FieldProblem {
+    FieldProblem::TypeMismatch {
+        expected,
+        found: found.type_name(),
+    }
+}
+
+impl FromFieldValue for FieldValue {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        Ok(value.cloned().unwrap_or(FieldValue::Null))
+    }
+}
+
+impl FromFieldValue for i64 {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match required(value)? {
+            FieldValue::Int(int) => Ok(*int),
+            other => Err(mismatch("Int", other)),
+        }
+    }
+}
+
+macro_rules! impl_from_int {
+    ($($int:ty),*) => {
+        $(
+            impl FromFieldValue for $int {
+                fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+                    let int = i64::from_field_value(value)?;
+                    <$int>::try_from(int).map_err(|_| FieldProblem::OutOfRange {
+                        value: int,
+                        target: stringify!($int),
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(i32, u32, u8, usize);
+
+impl FromFieldValue for f32 {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match required(value)? {
+            FieldValue::Float(float) => Ok(*float),
+            FieldValue::Int(int) => Ok(*int as f32),
+            other => Err(mismatch("Float", other)),
+        }
+    }
+}
+
+impl FromFieldValue for bool {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match required(value)? {
+            FieldValue::Bool(boolean) => Ok(*boolean),
+            other => Err(mismatch("Bool", other)),
+        }
+    }
+}
+
+impl FromFieldValue for String {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match required(value)? {
+            FieldValue::String(string) => Ok(string.clone()),
+            other => Err(mismatch("String", other)),
+        }
+    }
+}
+
+impl FromFieldValue for Color {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match required(value)? {
+            FieldValue::Color(color) => Ok(*color),
+            other => Err(mismatch("Color", other)),
+        }
+    }
+}
+
+impl FromFieldValue for IVec2 {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match required(value)? {
+            FieldValue::Point(point) => Ok(*point),
+            other => Err(mismatch("Point", other)),
+        }
+    }
+}
+
+impl FromFieldValue for EntityRef {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match required(value)? {
+            FieldValue::EntityRef(entity_ref) => Ok(entity_ref.clone()),
+            other => Err(mismatch("EntityRef", other)),
+        }
+    }
+}
+
+impl<T: FromFieldValue> FromFieldValue for Option<T> {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match value {
+            None | Some(FieldValue::Null) => Ok(None),
+            Some(value) => T::from_field_value(Some(value)).map(Some),
+        }
+    }
+
+    fn collect_links<'a>(&'a mut self, links: &mut Vec<&'a mut LinkedEntity>) {
+        if let Some(value) = self {
+            value.collect_links(links);
+        }
+    }
+}
+
+impl<T: FromFieldValue> FromFieldValue for Vec<T> {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        match required(value)? {
+            FieldValue::Array(items) => items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    T::from_field_value(Some(item)).map_err(|problem| FieldProblem::Item {
+                        index,
+                        problem: Box::new(problem),
+                    })
+                })
+                .collect(),
+            other => Err(mismatch("Array", other)),
+        }
+    }
+
+    fn collect_links<'a>(&'a mut self, links: &mut Vec<&'a mut LinkedEntity>) {
+        for value in self.iter_mut() {
+            value.collect_links(links);
+        }
+    }
+}
+
+/// An entity reference field, linked to the entity spawned for it while that entity
+/// exists; see `level_entities`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkedEntity {
+    pub target: EntityRef,
+    pub entity: Option<Entity>,
+}
+
+impl FromFieldValue for LinkedEntity {
+    fn from_field_value(value: Option<&FieldValue>) -> Result<Self, FieldProblem> {
+        EntityRef::from_field_value(value).map(|target| LinkedEntity {
+            target,
+            entity: None,
+        })
+    }
+
+    fn collect_links<'a>(&'a mut self, links: &mut Vec<&'a mut LinkedEntity>) {
+        links.push(self);
+    }
+}
+
+#[macro_export]
+macro_rules! ldtk_component {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty),*
+        }
+
+        impl $crate::ldtk_fields::FromFields for $name {
+            fn from_fields(
+                fields: &[(String, $crate::ldtk_fields::FieldValue)],
+            ) -> Result<Self, $crate::ldtk_fields::FieldError> {
+                Ok($name {
+                    $($field: $crate::ldtk_fields::field(fields, stringify!($field))?),*
+                })
+            }
+
+            fn collect_links<'a>(
+                &'a mut self,
+                links: &mut Vec<&'a mut $crate::ldtk_fields::LinkedEntity>,
+            ) {
+                $($crate::ldtk_fields::FromFieldValue::collect_links(&mut self.$field, links);)*
+            }
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! ldtk_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($variant:ident),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($variant),*
+        }
+
+        impl $crate::ldtk_fields::FromFieldValue for $name {
+            fn from_field_value(
+                value: Option<&$crate::ldtk_fields::FieldValue>,
+            ) -> Result<Self, $crate::ldtk_fields::FieldProblem> {
+                use $crate::ldtk_fields::{FieldProblem, FieldValue};
+                match value {
+                    None => Err(FieldProblem::Missing),
+                    Some(FieldValue::Null) => Err(FieldProblem::Empty),
+                    $(Some(FieldValue::Enum { value, .. }) if value == stringify!($variant) => {
+                        Ok($name::$variant)
+                    })*
+                    Some(FieldValue::Enum { enum_name, value }) => Err(FieldProblem::UnknownVariant {
+                        enum_name: enum_name.clone(),
+                        value: value.clone(),
+                        target: stringify!($name),
+                    }),
+                    Some(other) => Err(FieldProblem::TypeMismatch {
+                        expected: "Enum",
+                        found: other.type_name(),
+                    }),
+                }
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldProblem {
+    // e.g. tile fields, which the game has no use for
+    UnsupportedType(String),
+    InvalidValue {
+        field_type: String,
+        value: String,
+    },
+    Missing,
+    Empty,
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    OutOfRange {
+        value: i64,
+        target: &'static str,
+    },
+    UnknownVariant {
+        enum_name: String,
+        value: String,
+        target: &'static str,
+    },
+    // in an array
+    Item {
+        index: usize,
+        problem: Box<FieldProblem>,
+    },
+}
+
+impl fmt::Display for FieldProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldProblem::UnsupportedType(field_type) => {
+                write!(f, "{} fields are not supported", field_type)
+            }
+            FieldProblem::InvalidValue { field_type, value } => {
+                write!(f, "{} is not a valid {} value", value, field_type)
+            }
+            FieldProblem::Missing => write!(f, "missing"),
+            FieldProblem::Empty => write!(f, "needs a value"),
+            FieldProblem::TypeMismatch { expected, found } => {
+                write!(f, "expected {} but found {}", expected, found)
+            }
+            FieldProblem::OutOfRange { value, target } => {
+                write!(f, "{} does not fit in {}", value, target)
+            }
+            FieldProblem::UnknownVariant {
+                enum_name,
+                value,
+                target,
+            } => write!(f, "{}.{} has no {} variant", enum_name, value, target),
+            FieldProblem::Item { index, problem } => write!(f, "item {}: {}", index, problem),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub problem: FieldProblem,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "field \"{}\": {}", self.field, self.problem)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    ldtk_enum! {
+        #[derive(Debug, PartialEq)]
+        enum Mood {
+            Calm,
+            Angry,
+        }
+    }
+
+    ldtk_component! {
+        #[derive(Debug)]
+        struct Guard {
+            pub life: u8,
+            pub mood: Mood,
+            pub speed: Option<f32>,
+            pub partners: Vec<LinkedEntity>,
+        }
+    }
+
+    fn entity_ref(iid: &str) -> Value {
+        json!({"entityIid": iid, "layerIid": "layer", "levelIid": "level", "worldIid": "world"})
+    }
+
+    fn guard_fields() -> Vec<(String, FieldValue)> {
+        vec![
+            ("Life".to_string(), FieldValue::Int(3)),
+            (
+                "Mood".to_string(),
+                FieldValue::parse("LocalEnum.Mood", &json!("Angry")).unwrap(),
+            ),
+            ("Speed".to_string(), FieldValue::Null),
+            (
+                "Partners".to_string(),
+                FieldValue::parse(
+                    "Array<EntityRef>",
+                    &json!([entity_ref("first"), entity_ref("second")]),
+                )
+                .unwrap(),
+            ),
+        ]
+    }
+
+    fn with(name: &str, value: FieldValue) -> Vec<(String, FieldValue)> {
+        let mut fields = guard_fields();
+        fields.retain(|(identifier, _)| identifier != name);
+        fields.push((name.to_string(), value));
+        fields
+    }
+
+    fn problem(fields: &[(String, FieldValue)]) -> FieldError {
+        Guard::from_fields(fields).unwrap_err()
+    }
+
+    #[test]
+    fn values_are_parsed_by_type() {
+        assert_eq!(FieldValue::parse("Int", &json!(4)), Ok(FieldValue::Int(4)));
+        assert_eq!(
+            FieldValue::parse("Float", &json!(0.5)),
+            Ok(FieldValue::Float(0.5))
+        );
+        assert_eq!(
+            FieldValue::parse("Multilines", &json!("a\nb")),
+            Ok(FieldValue::String("a\nb".to_string()))
+        );
+        assert_eq!(
+            FieldValue::parse("Point", &json!({"cx": 2, "cy": 7})),
+            Ok(FieldValue::Point(IVec2::new(2, 7)))
+        );
+        assert_eq!(
+            FieldValue::parse("ExternalEnum.Mood", &json!("Calm")),
+            Ok(FieldValue::Enum {
+                enum_name: "Mood".to_string(),
+                value: "Calm".to_string()
+            })
+        );
+        assert!(matches!(
+            FieldValue::parse("Color", &json!("#ff0000")),
+            Ok(FieldValue::Color(_))
+        ));
+        assert_eq!(
+            FieldValue::parse("Array<Int>", &json!([1, null])),
+            Ok(FieldValue::Array(vec![
+                FieldValue::Int(1),
+                FieldValue::Null
+            ]))
+        );
+        assert_eq!(
+            FieldValue::parse("Bool", &Value::Null),
+            Ok(FieldValue::Null)
+        );
+    }
+
+    #[test]
+    fn bad_values_are_reported() {
+        assert_eq!(
+            FieldValue::parse("Int", &json!("four")),
+            Err(FieldProblem::InvalidValue {
+                field_type: "Int".to_string(),
+                value: "\"four\"".to_string()
+            })
+        );
+        assert!(matches!(
+            FieldValue::parse("Color", &json!("red")),
+            Err(FieldProblem::InvalidValue { .. })
+        ));
+        assert_eq!(
+            FieldValue::parse("Tile", &json!({"tilesetUid": 1})),
+            Err(FieldProblem::UnsupportedType("Tile".to_string()))
+        );
+    }
+
+    #[test]
+    fn components_read_fields_ignoring_case() {
+        let guard = Guard::from_fields(&guard_fields()).unwrap();
+        assert_eq!(guard.life, 3);
+        assert_eq!(guard.mood, Mood::Angry);
+        assert_eq!(guard.speed, None);
+        assert_eq!(guard.partners.len(), 2);
+    }
+
+    #[test]
+    fn mismatched_values_are_errors() {
+        let error = problem(&with("Life", FieldValue::Bool(true)));
+        assert_eq!(error.field, "life");
+        assert_eq!(
+            error.problem,
+            FieldProblem::TypeMismatch {
+                expected: "Int",
+                found: "Bool"
+            }
+        );
+    }
+
+    #[test]
+    fn missing_and_empty_fields_are_errors_unless_optional() {
+        let mut fields = guard_fields();
+        fields.retain(|(identifier, _)| identifier != "Life");
+        assert_eq!(problem(&fields).problem, FieldProblem::Missing);
+        assert_eq!(
+            problem(&with("Life", FieldValue::Null)).problem,
+            FieldProblem::Empty
+        );
+
+        fields = guard_fields();
+        fields.retain(|(identifier, _)| identifier != "Speed");
+        assert_eq!(Guard::from_fields(&fields).unwrap().speed, None);
+        let guard = Guard::from_fields(&with("Speed", FieldValue::Int(2))).unwrap();
+        assert_eq!(guard.speed, Some(2.0));
+    }
+
+    #[test]
+    fn ints_must_fit_the_field() {
+        assert_eq!(
+            problem(&with("Life", FieldValue::Int(300))).problem,
+            FieldProblem::OutOfRange {
+                value: 300,
+                target: "u8"
+            }
+        );
+    }
+
+    #[test]
+    fn unknown_enum_values_are_errors() {
+        let value = FieldValue::parse("LocalEnum.Mood", &json!("Sleepy")).unwrap();
+        assert_eq!(
+            problem(&with("Mood", value)).problem,
+            FieldProblem::UnknownVariant {
+                enum_name: "Mood".to_string(),
+                value: "Sleepy".to_string(),
+                target: "Mood"
+            }
+        );
+    }
+
+    #[test]
+    fn entity_ref_arrays_are_collected_for_linking() {
+        let mut guard = Guard::from_fields(&guard_fields()).unwrap();
+        let mut links = Vec::new();
+        guard.collect_links(&mut links);
+        let targets: Vec<&str> = links
+            .iter()
+            .map(|link| link.target.entity_iid.as_str())
+            .collect();
+        assert_eq!(targets, vec!["first", "second"]);
+
+        for link in links {
+            link.entity = Some(Entity::from_raw(7));
+        }
+        assert!(guard
+            .partners
+            .iter()
+            .all(|partner| partner.entity == Some(Entity::from_raw(7))));
+        assert_eq!(guard.partners[0].target.world_iid.as_deref(), Some("world"));
+    }
+}
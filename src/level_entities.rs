@@ -0,0 +1,476 @@
+use std::any::TypeId;
+
+use bevy::ecs::system::EntityCommands;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+
+use crate::ldtk::{EntityInstance, FieldInstance, LayerInstance, LdtkProject, Level};
+use crate::ldtk_fields::{FieldError, FieldValue, FromFields};
+use crate::level_streaming::{stream_levels, LevelRoot, LevelStates, LevelWorld};
+
+pub struct LevelEntitiesPlugin;
+
+impl Plugin for LevelEntitiesPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<EntityRegistry>()
+            .init_resource::<LevelEntityIndex>()
+            .add_system(spawn_level_entities.after(stream_levels))
+            // levels are despawned through commands at the end of Update, and removals
+            // are only seen until the end of the frame
+            .add_system_to_stage(CoreStage::PostUpdate, forget_despawned_entities);
+    }
+}
+
+/* Gameplay entities placed in LDtk. Each entity identifier (PlayerStart, Enemy,
+Door...) has spawners registered by the plugins that own it:
+
+    app.register_ldtk_entity("Pickup", |entity, instance| { entity.insert(...); });
+    // a component built from the instance's fields, see `ldtk_fields`
+    app.add_ldtk_component::<Patrol>("Enemy");
+
+Spawners get a fresh entity, already tagged with `LevelEntity`, and fill it in from
+the instance. Instances without a spawner are skipped with a warning, and so are
+those removed in play (see `LevelState`) when their level is loaded again.
+ */
+pub type EntitySpawner = Box<dyn Fn(&mut EntityCommands, &LdtkEntity) + Send + Sync>;
+
+#[derive(Default)]
+pub struct EntityRegistry {
+    spawners: HashMap<String, Vec<EntitySpawner>>,
+    // components whose entity references are already linked by a system
+    linked: HashSet<TypeId>,
+}
+
+impl EntityRegistry {
+    /// Adds a spawner for `identifier`; all spawners of an identifier run, in order.
+    pub fn register(&mut self, identifier: &str, spawner: EntitySpawner) {
+        self.spawners
+            .entry(identifier.to_string())
+            .or_default()
+            .push(spawner);
+    }
+
+    pub fn get(&self, identifier: &str) -> &[EntitySpawner] {
+        self.spawners.get(identifier).map_or(&[], Vec::as_slice)
+    }
+}
+
+pub trait RegisterLdtkEntityExt {
+    fn register_ldtk_entity(
+        &mut self,
+        identifier: &str,
+        spawner: impl Fn(&mut EntityCommands, &LdtkEntity) + Send + Sync + 'static,
+    ) -> &mut Self;
+
+    /// Inserts `T`, read from the fields, on every `identifier` entity, and links its
+    /// entity references.
+    fn add_ldtk_component<T: Component + FromFields>(&mut self, identifier: &str) -> &mut Self;
+
+    /// Inserts `T`, read from the level's fields, on level roots.
+    fn add_ldtk_level_component<T: Component + FromFields>(&mut self) -> &mut Self;
+}
+
+impl RegisterLdtkEntityExt for App {
+    fn register_ldtk_entity(
+        &mut self,
+        identifier: &str,
+        spawner: impl Fn(&mut EntityCommands, &LdtkEntity) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.world
+            .get_resource_or_insert_with(EntityRegistry::default)
+            .register(identifier, Box::new(spawner));
+        self
+    }
+
+    fn add_ldtk_component<T: Component + FromFields>(&mut self, identifier: &str) -> &mut Self {
+        self.register_ldtk_entity(identifier, |entity, instance| {
+            match instance.component::<T>() {
+                Ok(component) => {
+                    entity.insert(component);
+                }
+                Err(err) => warn!(
+                    "{}: {} {}: {}",
+                    instance.level, instance.identifier, instance.iid, err
+                ),
+            }
+        });
+        add_link_system::<T>(self)
+    }
+
+    fn add_ldtk_level_component<T: Component + FromFields>(&mut self) -> &mut Self {
+        self.add_system(insert_level_component::<T>);
+        add_link_system::<T>(self)
+    }
+}
+
+// one linking system per component type, however many identifiers it is added to
+fn add_link_system<T: Component + FromFields>(app: &mut App) -> &mut App {
+    let added = app
+        .world
+        .get_resource_or_insert_with(EntityRegistry::default)
+        .linked
+        .insert(TypeId::of::<T>());
+    if added {
+        app.add_system(link_entities::<T>);
+    }
+    app
+}
+
+/// An LDtk entity instance, as handed to its spawner.
+pub struct LdtkEntity {
+    pub identifier: String,
+    pub iid: String,
+    pub level: String,
+    // world position of the pivot
+    pub position: Vec2,
+    pub size: Vec2,
+    // fraction of the size from the top-left corner, as in LDtk
+    pub pivot: Vec2,
+    // world position of the layer's top-left corner, and its cell size
+    pub layer_origin: Vec2,
+    pub grid_size: f32,
+    // fields that could not be typed are left out, with a warning
+    pub fields: Vec<(String, FieldValue)>,
+}
+
+impl LdtkEntity {
+    pub fn field(&self, identifier: &str) -> Option<&FieldValue> {
+        self.fields
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(identifier))
+            .map(|(_, value)| value)
+    }
+
+    pub fn component<T: FromFields>(&self) -> Result<T, FieldError> {
+        T::from_fields(&self.fields)
+    }
+
+    pub fn center(&self) -> Vec2 {
+        self.position + (Vec2::splat(0.5) - self.pivot) * self.size * Vec2::new(1.0, -1.0)
+    }
+
+    /// World position of the centre of a cell of the layer, as given by Point fields.
+    pub fn cell_center(&self, cell: IVec2) -> Vec2 {
+        self.layer_origin
+            + (cell.as_vec2() + Vec2::splat(0.5)) * self.grid_size * Vec2::new(1.0, -1.0)
+    }
+
+    pub fn bounds(&self) -> EntityBounds {
+        let half_size = self.size / 2.0;
+        EntityBounds {
+            min: self.center() - half_size,
+            max: self.center() + half_size,
+        }
+    }
+}
+
+/// World position of an entity instance's pivot, in a level whose top-left corner is
+/// at `origin`.
+pub fn instance_position(origin: Vec2, layer: &LayerInstance, instance: &EntityInstance) -> Vec2 {
+    // y grows down in the level and up in the world
+    origin
+        + Vec2::new(
+            (instance.px[0] + layer.px_total_offset_x) as f32,
+            -(instance.px[1] + layer.px_total_offset_y) as f32,
+        )
+}
+
+/// World rect an LDtk entity covers, as (min, max) corners.
+#[derive(Component, Clone, Copy)]
+pub struct EntityBounds {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl EntityBounds {
+    /// Whether a box around `center` overlaps the rect.
+    pub fn overlaps(&self, center: Vec2, half_size: Vec2) -> bool {
+        center.x + half_size.x > self.min.x
+            && center.x - half_size.x < self.max.x
+            && center.y + half_size.y > self.min.y
+            && center.y - half_size.y < self.max.y
+    }
+}
+
+/// Spawned from an LDtk entity instance, or by one (e.g. a trigger's prefabs); either
+/// way it is despawned with its level.
+#[derive(Component)]
+pub struct LevelEntity {
+    pub iid: String,
+    pub level: String,
+}
+
+/// Custom fields of the level, on its root entity.
+#[derive(Component)]
+pub struct LevelFields {
+    pub level: String,
+    pub fields: Vec<(String, FieldValue)>,
+}
+
+/// Spawned level entities by LDtk iid, for linking entity references.
+#[derive(Default)]
+pub struct LevelEntityIndex {
+    entities: HashMap<String, Entity>,
+}
+
+impl LevelEntityIndex {
+    pub fn get(&self, iid: &str) -> Option<Entity> {
+        self.entities.get(iid).copied()
+    }
+}
+
+fn forget_despawned_entities(
+    mut index: ResMut<LevelEntityIndex>,
+    removed: RemovedComponents<LevelEntity>,
+) {
+    let removed: HashSet<Entity> = removed.iter().collect();
+    if !removed.is_empty() {
+        index.entities.retain(|_, entity| !removed.contains(entity));
+    }
+}
+
+// points the links of new components, and of all of them when entities come and go
+fn link_entities<T: Component + FromFields>(
+    index: Res<LevelEntityIndex>,
+    mut query: Query<&mut T>,
+) {
+    for mut component in query.iter_mut() {
+        if !component.is_added() && !index.is_changed() {
+            continue;
+        }
+        let mut links = Vec::new();
+        component.collect_links(&mut links);
+        for link in links {
+            link.entity = index.get(&link.target.entity_iid);
+        }
+    }
+}
+
+fn insert_level_component<T: Component + FromFields>(
+    mut commands: Commands,
+    levels: Query<(Entity, &LevelFields), Added<LevelFields>>,
+) {
+    for (entity, level) in levels.iter() {
+        match T::from_fields(&level.fields) {
+            Ok(component) => {
+                commands.entity(entity).insert(component);
+            }
+            Err(err) => warn!("{}: level {}", level.level, err),
+        }
+    }
+}
+
+fn spawn_level_entities(
+    mut commands: Commands,
+    registry: Res<EntityRegistry>,
+    projects: Res<Assets<LdtkProject>>,
+    states: Res<LevelStates>,
+    mut index: ResMut<LevelEntityIndex>,
+    world: Option<Res<LevelWorld>>,
+    roots: Query<(Entity, &LevelRoot), Added<LevelRoot>>,
+) {
+    let project = match world.and_then(|world| projects.get(&world.project)) {
+        Some(project) => project,
+        None => return,
+    };
+    for (root, level_root) in roots.iter() {
+        if let Some(level) = project.level(&level_root.level) {
+            let removed = states.get(&level.identifier).map(|state| &state.removed);
+            spawn_entities(
+                &mut commands,
+                &registry,
+                &mut index,
+                level,
+                level_root,
+                root,
+                |iid| removed.map_or(false, |removed| removed.contains(iid)),
+            );
+        }
+    }
+}
+
+fn spawn_entities(
+    commands: &mut Commands,
+    registry: &EntityRegistry,
+    index: &mut LevelEntityIndex,
+    level: &Level,
+    level_root: &LevelRoot,
+    root: Entity,
+    removed: impl Fn(&str) -> bool,
+) {
+    commands.entity(root).insert(LevelFields {
+        level: level.identifier.clone(),
+        fields: typed_fields(&level.field_instances, |err| {
+            warn!("{}: level {}", level.identifier, err)
+        }),
+    });
+
+    let mut unregistered = HashSet::default();
+    for layer in level.layers() {
+        for instance in layer.entity_instances.iter() {
+            if removed(&instance.iid) {
+                continue;
+            }
+            let spawners = registry.get(&instance.identifier);
+            if spawners.is_empty() {
+                if unregistered.insert(instance.identifier.as_str()) {
+                    warn!(
+                        "{}: no spawner registered for LDtk entity {}",
+                        level.identifier, instance.identifier
+                    );
+                }
+                continue;
+            }
+
+            let fields = typed_fields(&instance.field_instances, |err| {
+                warn!(
+                    "{}: {} {}: {}",
+                    level.identifier, instance.identifier, instance.iid, err
+                )
+            });
+            let position = instance_position(level_root.origin, layer, instance);
+            let ldtk_entity = LdtkEntity {
+                identifier: instance.identifier.clone(),
+                iid: instance.iid.clone(),
+                level: level.identifier.clone(),
+                position,
+                size: Vec2::new(instance.width as f32, instance.height as f32),
+                pivot: Vec2::new(instance.pivot[0], instance.pivot[1]),
+                layer_origin: position - Vec2::new(instance.px[0] as f32, -instance.px[1] as f32),
+                grid_size: layer.grid_size as f32,
+                fields,
+            };
+
+            let mut entity = commands.spawn();
+            entity
+                .insert(LevelEntity {
+                    iid: instance.iid.clone(),
+                    level: level.identifier.clone(),
+                })
+                .insert(ldtk_entity.bounds());
+            for spawner in spawners {
+                spawner(&mut entity, &ldtk_entity);
+            }
+            index.entities.insert(instance.iid.clone(), entity.id());
+        }
+    }
+}
+
+// fields that cannot be typed are reported and left out
+fn typed_fields(
+    instances: &[FieldInstance],
+    report: impl Fn(FieldError),
+) -> Vec<(String, FieldValue)> {
+    let mut fields = Vec::new();
+    for field in instances {
+        match field.typed_value() {
+            Ok(value) => fields.push((field.identifier.clone(), value)),
+            Err(err) => report(err),
+        }
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::AssetPlugin;
+    use bevy::core::CorePlugin;
+
+    use super::*;
+    use crate::ldtk_component;
+    use crate::ldtk_fields::{EntityRef, LinkedEntity};
+
+    ldtk_component! {
+        #[derive(Component)]
+        struct Lever {
+            pub door: Option<LinkedEntity>,
+        }
+    }
+
+    #[derive(Component)]
+    struct Unload;
+
+    // stands in for `stream_levels`, which despawns levels through commands
+    fn unload_levels(mut commands: Commands, levels: Query<Entity, With<Unload>>) {
+        for level in levels.iter() {
+            commands.entity(level).despawn_recursive();
+        }
+    }
+
+    fn level_entity(app: &mut App, level: &str, iid: &str) -> Entity {
+        let entity = app
+            .world
+            .spawn()
+            .insert(LevelEntity {
+                iid: iid.to_string(),
+                level: level.to_string(),
+            })
+            .id();
+        app.world
+            .resource_mut::<LevelEntityIndex>()
+            .entities
+            .insert(iid.to_string(), entity);
+        entity
+    }
+
+    #[test]
+    fn despawned_levels_leave_the_index_and_links() {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin)
+            .add_plugin(AssetPlugin)
+            .add_asset::<LdtkProject>()
+            .init_resource::<LevelStates>()
+            .add_plugin(LevelEntitiesPlugin)
+            .add_ldtk_component::<Lever>("Lever")
+            .add_system(unload_levels);
+
+        let door = level_entity(&mut app, "Cellar", "door");
+        let cellar = app.world.spawn().push_children(&[door]).id();
+        let lever = level_entity(&mut app, "Hall", "lever");
+        app.world.entity_mut(lever).insert(Lever {
+            door: Some(LinkedEntity {
+                target: EntityRef {
+                    entity_iid: "door".to_string(),
+                    layer_iid: "entities".to_string(),
+                    level_iid: "cellar".to_string(),
+                    world_iid: None,
+                },
+                entity: None,
+            }),
+        });
+        app.update();
+        let linked = |app: &App| {
+            app.world
+                .get::<Lever>(lever)
+                .unwrap()
+                .door
+                .as_ref()
+                .unwrap()
+                .entity
+        };
+        assert_eq!(linked(&app), Some(door));
+
+        app.world.entity_mut(cellar).insert(Unload);
+        app.update();
+        app.update();
+        assert!(app.world.get_entity(door).is_none());
+        assert_eq!(app.world.resource::<LevelEntityIndex>().get("door"), None);
+        assert_eq!(
+            app.world.resource::<LevelEntityIndex>().get("lever"),
+            Some(lever)
+        );
+        assert_eq!(linked(&app), None);
+    }
+
+    #[test]
+    fn components_are_linked_once_per_type() {
+        let mut app = App::new();
+        app.add_ldtk_component::<Lever>("Lever")
+            .add_ldtk_component::<Lever>("Switch")
+            .add_ldtk_level_component::<Lever>();
+        let registry = app.world.resource::<EntityRegistry>();
+        assert_eq!(registry.get("Lever").len(), 1);
+        assert_eq!(registry.get("Switch").len(), 1);
+        assert_eq!(registry.linked.len(), 1);
+    }
+}
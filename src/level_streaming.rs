@@ -0,0 +1,432 @@
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use bevy_parallax::ParallaxCameraComponent;
+
+use crate::asset_check::LoadingState;
+use crate::collision::CollisionGrid;
+use crate::ldtk::{LdtkProject, Level, LEVEL_PROJECT};
+use crate::level_entities::{EntityRegistry, LevelEntity, LevelFields};
+use crate::tilemap::spawn_tile_layers;
+
+pub const START_LEVEL: &str = "Basic_1";
+
+pub struct LevelStreamingPlugin;
+
+impl Plugin for LevelStreamingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LevelStates>()
+            .add_system_set(SystemSet::on_enter(LoadingState::Ready).with_system(load_level_world))
+            .add_system(stream_levels);
+    }
+}
+
+/* The levels of the LDtk world around the camera. The level the camera is in and its
+neighbours stay loaded, the others are unloaded; what changed in a level is kept in
+`LevelStates` so it comes back the same.
+
+LDtk world coordinates are placed so the start level sits where the collision grid
+loaded at startup puts it, centred on the origin. All levels share that grid's cells,
+so levels should be placed on the grid in LDtk.
+ */
+pub struct LevelWorld {
+    pub project: Handle<LdtkProject>,
+    // level the camera is in, or was last in
+    pub current: String,
+    // world position of LDtk's world origin, and of the start level's top-left corner
+    offset: Option<Vec2>,
+    cell_origin: Vec2,
+    loaded: HashMap<String, Entity>,
+    // levels whose state is kept at the next update
+    save: HashSet<String>,
+    // roots of levels being loaded again, despawned at the next update
+    stale: Vec<(String, Entity)>,
+}
+
+impl LevelWorld {
+    pub fn root(&self, level: &str) -> Option<Entity> {
+        self.loaded.get(level).copied()
+    }
+
+    pub fn loaded(&self) -> impl Iterator<Item = &str> {
+        self.loaded.keys().map(String::as_str)
+    }
+
+    /// Keeps what changed in the level so far in `LevelStates`, as if it was unloaded.
+    pub fn save(&mut self, level: &str) {
+        self.save.insert(level.to_string());
+    }
+
+    /// Loads the level again from its `LevelState`, dropping what changed since it was
+    /// last saved. Its root is None until the new one is spawned.
+    pub fn reload(&mut self, level: &str) {
+        if let Some(root) = self.loaded.remove(level) {
+            self.save.remove(level);
+            self.stale.push((level.to_string(), root));
+        }
+    }
+
+    /// World position of the level's top-left corner, once the project has loaded.
+    pub fn origin(&self, level: &Level) -> Option<Vec2> {
+        self.offset
+            .map(|offset| offset + Vec2::new(level.world_x as f32, -level.world_y as f32))
+    }
+
+    pub fn contains(&self, level: &Level, position: Vec2) -> bool {
+        self.origin(level).map_or(false, |origin| {
+            position.x >= origin.x
+                && position.x < origin.x + level.px_wid as f32
+                && position.y <= origin.y
+                && position.y > origin.y - level.px_hei as f32
+        })
+    }
+
+    // first cell of the level in the shared grid, counted from the start level's
+    fn level_cell(&self, origin: Vec2, cell_size: f32) -> IVec2 {
+        let lattice = CollisionGrid {
+            cell_size,
+            origin: self.cell_origin,
+            ..Default::default()
+        };
+        let (x, y) = lattice.cell_at(origin + Vec2::new(0.5, -0.5));
+        IVec2::new(x, y)
+    }
+}
+
+/// Entity holding a loaded level's layers, at its top-left corner.
+#[derive(Component)]
+pub struct LevelRoot {
+    pub level: String,
+    pub origin: Vec2,
+    pub size: Vec2,
+}
+
+/// What changed in levels since they were first loaded, by level identifier.
+#[derive(Default)]
+pub struct LevelStates {
+    levels: HashMap<String, LevelState>,
+}
+
+impl LevelStates {
+    pub fn get(&self, level: &str) -> Option<&LevelState> {
+        self.levels.get(level)
+    }
+
+    pub fn get_mut(&mut self, level: &str) -> &mut LevelState {
+        self.levels.entry(level.to_string()).or_default()
+    }
+}
+
+#[derive(Default)]
+pub struct LevelState {
+    // IntGrid values as they were when the level was unloaded
+    pub int_grid: Option<Vec<i32>>,
+    // iids of entity instances gone for good, e.g. defeated enemies
+    pub removed: HashSet<String>,
+    // anything else the level's entities remember, e.g. the iids of opened doors
+    pub flags: HashSet<String>,
+}
+
+fn load_level_world(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(LevelWorld {
+        project: asset_server.load(LEVEL_PROJECT),
+        current: START_LEVEL.to_string(),
+        offset: None,
+        cell_origin: Vec2::ZERO,
+        loaded: HashMap::default(),
+        save: HashSet::default(),
+        stale: Vec::new(),
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn stream_levels(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    projects: Res<Assets<LdtkProject>>,
+    registry: Res<EntityRegistry>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut grid: ResMut<CollisionGrid>,
+    mut states: ResMut<LevelStates>,
+    world: Option<ResMut<LevelWorld>>,
+    cameras: Query<&Transform, With<ParallaxCameraComponent>>,
+    roots: Query<(&LevelRoot, Option<&LevelFields>)>,
+    level_entities: Query<(Entity, &LevelEntity)>,
+) {
+    let mut world = match world {
+        Some(world) => world,
+        None => return,
+    };
+    let project_handle = world.project.clone();
+    let project = match projects.get(&project_handle) {
+        Some(project) => project,
+        None => return,
+    };
+    if world.offset.is_none() {
+        let start = match project.level(&world.current) {
+            Some(level) => level,
+            None => match project.levels.first() {
+                Some(level) => {
+                    error!(
+                        "{}: no level named {}, starting in {}",
+                        LEVEL_PROJECT, world.current, level.identifier
+                    );
+                    level
+                }
+                None => return,
+            },
+        };
+        // the start level's collision grid is centred on the origin
+        let size = start.int_grid().map_or(
+            Vec2::new(start.px_wid as f32, start.px_hei as f32),
+            |layer| Vec2::new(layer.c_wid as f32, layer.c_hei as f32) * layer.grid_size as f32,
+        );
+        world.cell_origin = Vec2::new(-size.x, size.y) / 2.0;
+        world.offset =
+            Some(world.cell_origin - Vec2::new(start.world_x as f32, -start.world_y as f32));
+        world.current = start.identifier.clone();
+    }
+
+    // between levels, the camera stays in the last one
+    if let Ok(camera) = cameras.get_single() {
+        let position = camera.translation.truncate();
+        if let Some(level) = project
+            .levels
+            .iter()
+            .find(|level| world.contains(level, position))
+        {
+            if level.identifier != world.current {
+                world.current = level.identifier.clone();
+            }
+        }
+    }
+    let current = match project.level(&world.current) {
+        Some(level) => level,
+        None => return,
+    };
+    let mut wanted: HashSet<&str> = HashSet::default();
+    wanted.insert(current.identifier.as_str());
+    for neighbour in current.neighbours.iter() {
+        if let Some(level) = project.level_by_iid(&neighbour.level_iid) {
+            wanted.insert(level.identifier.as_str());
+        }
+    }
+
+    for level_name in std::mem::take(&mut world.save) {
+        let root = match world.root(&level_name) {
+            Some(root) => root,
+            None => continue,
+        };
+        if let (Ok((level_root, fields)), Some(level)) =
+            (roots.get(root), project.level(&level_name))
+        {
+            save_level_state(
+                states.get_mut(&level_name),
+                level,
+                Some(read_int_grid(&grid, level_root, level)),
+                fields.is_some(),
+                &registry,
+                &level_entities,
+            );
+        }
+    }
+    let stale = std::mem::take(&mut world.stale);
+    for (level_name, root) in stale.iter() {
+        despawn_level(&mut commands, level_name, *root, &level_entities);
+    }
+
+    let unload: Vec<String> = world
+        .loaded
+        .keys()
+        .filter(|level| !wanted.contains(level.as_str()))
+        .cloned()
+        .collect();
+    let load: Vec<&Level> = wanted
+        .iter()
+        .filter(|level| !world.loaded.contains_key(**level))
+        .filter_map(|level| project.level(level))
+        .collect();
+    if unload.is_empty() && load.is_empty() && stale.is_empty() {
+        return;
+    }
+
+    // IntGrid values of the levels staying loaded, which may have changed in play
+    let mut int_grids: HashMap<String, Vec<i32>> = HashMap::default();
+    for (level_name, root) in world.loaded.iter() {
+        if let (Ok((level_root, _)), Some(level)) = (roots.get(*root), project.level(level_name)) {
+            int_grids.insert(level_name.clone(), read_int_grid(&grid, level_root, level));
+        }
+    }
+
+    for level_name in unload {
+        let root = match world.loaded.remove(&level_name) {
+            Some(root) => root,
+            None => continue,
+        };
+        // entities spawned from the level that are gone by now were removed in play
+        let entities_spawned = matches!(roots.get(root), Ok((_, Some(_))));
+        if let Some(level) = project.level(&level_name) {
+            save_level_state(
+                states.get_mut(&level_name),
+                level,
+                int_grids.remove(&level_name),
+                entities_spawned,
+                &registry,
+                &level_entities,
+            );
+        }
+        despawn_level(&mut commands, &level_name, root, &level_entities);
+    }
+
+    for level in load {
+        let origin = match world.origin(level) {
+            Some(origin) => origin,
+            None => continue,
+        };
+        let root = commands
+            .spawn_bundle(TransformBundle::from_transform(
+                Transform::from_translation(origin.extend(0.0)),
+            ))
+            .insert(LevelRoot {
+                level: level.identifier.clone(),
+                origin,
+                size: Vec2::new(level.px_wid as f32, level.px_hei as f32),
+            })
+            .id();
+        spawn_tile_layers(
+            &mut commands,
+            &asset_server,
+            &mut materials,
+            &project_handle,
+            project,
+            level,
+            root,
+            origin,
+        );
+        world.loaded.insert(level.identifier.clone(), root);
+
+        let saved = states
+            .get(&level.identifier)
+            .and_then(|state| state.int_grid.clone());
+        if let Some(values) =
+            saved.or_else(|| level.int_grid().map(|layer| layer.int_grid_csv.clone()))
+        {
+            int_grids.insert(level.identifier.clone(), values);
+        }
+    }
+
+    *grid = world_collision_grid(project, &world, grid.cell_size, &int_grids);
+}
+
+// records what changed in a loaded level into its state
+fn save_level_state(
+    state: &mut LevelState,
+    level: &Level,
+    int_grid: Option<Vec<i32>>,
+    entities_spawned: bool,
+    registry: &EntityRegistry,
+    level_entities: &Query<(Entity, &LevelEntity)>,
+) {
+    state.int_grid = int_grid;
+    if !entities_spawned {
+        return;
+    }
+    let alive: HashSet<&str> = level_entities
+        .iter()
+        .filter(|(_, entity)| entity.level == level.identifier)
+        .map(|(_, entity)| entity.iid.as_str())
+        .collect();
+    for instance in level
+        .layers()
+        .iter()
+        .flat_map(|layer| layer.entity_instances.iter())
+    {
+        if !registry.get(&instance.identifier).is_empty() && !alive.contains(instance.iid.as_str())
+        {
+            state.removed.insert(instance.iid.clone());
+        }
+    }
+}
+
+fn despawn_level(
+    commands: &mut Commands,
+    level_name: &str,
+    root: Entity,
+    level_entities: &Query<(Entity, &LevelEntity)>,
+) {
+    for (entity, level_entity) in level_entities.iter() {
+        if level_entity.level == level_name {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    commands.entity(root).despawn_recursive();
+}
+
+// the level's cells in `grid`, row by row
+fn read_int_grid(grid: &CollisionGrid, level_root: &LevelRoot, level: &Level) -> Vec<i32> {
+    let (width, height) = level
+        .int_grid()
+        .map_or((0, 0), |layer| (layer.c_wid, layer.c_hei));
+    let (first_x, first_y) = grid.cell_at(level_root.origin + Vec2::new(0.5, -0.5));
+    let mut values = Vec::with_capacity((width * height).max(0) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            values.push(grid.value(first_x + x, first_y + y));
+        }
+    }
+    values
+}
+
+/// One collision grid covering all loaded levels; cells between levels are empty.
+fn world_collision_grid(
+    project: &LdtkProject,
+    world: &LevelWorld,
+    cell_size: f32,
+    int_grids: &HashMap<String, Vec<i32>>,
+) -> CollisionGrid {
+    // each level's first cell and size in cells
+    let mut blocks = Vec::new();
+    for level in world.loaded().filter_map(|level| project.level(level)) {
+        let (layer, origin) = match (level.int_grid(), world.origin(level)) {
+            (Some(layer), Some(origin)) => (layer, origin),
+            _ => continue,
+        };
+        if let Some(values) = int_grids.get(&level.identifier) {
+            let first = world.level_cell(origin, cell_size);
+            blocks.push((first, IVec2::new(layer.c_wid, layer.c_hei), values));
+        }
+    }
+    let min = blocks
+        .iter()
+        .map(|(first, _, _)| *first)
+        .fold(IVec2::splat(i32::MAX), IVec2::min);
+    let max = blocks
+        .iter()
+        .map(|(first, size, _)| *first + *size)
+        .fold(IVec2::splat(i32::MIN), IVec2::max);
+    if blocks.is_empty() {
+        return CollisionGrid {
+            cell_size,
+            ..Default::default()
+        };
+    }
+
+    let size = max - min;
+    let mut values = vec![0; (size.x * size.y) as usize];
+    for (first, block_size, block) in blocks {
+        for y in 0..block_size.y {
+            for x in 0..block_size.x {
+                let (to_x, to_y) = (first.x - min.x + x, first.y - min.y + y);
+                if let Some(value) = block.get((y * block_size.x + x) as usize) {
+                    values[(to_y * size.x + to_x) as usize] = *value;
+                }
+            }
+        }
+    }
+    CollisionGrid {
+        width: size.x as usize,
+        height: size.y as usize,
+        cell_size,
+        origin: world.cell_origin + Vec2::new(min.x as f32, -min.y as f32) * cell_size,
+        values,
+    }
+}
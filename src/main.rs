@@ -1,15 +1,61 @@
+use std::time::Duration;
+
 use bevy::prelude::State;
 use bevy::prelude::*;
+use serde::Deserialize;
 // use bevy_ecs_ldtk::{LdtkPlugin};
 // use bevy_parallax::{ParallaxResource, LayerData};
 
+mod abilities;
 mod animated_sprite;
+mod aseprite;
+mod asset_check;
+mod atlas;
+mod autotile;
+mod character_controller;
+mod checkpoints;
+mod collision;
+mod combat;
 mod custom_parallax;
+mod doors;
+mod equipment;
 mod hello;
-
+mod ldtk;
+mod ldtk_fields;
+mod level_entities;
+mod level_streaming;
+mod prefab;
+mod render_layers;
+mod simulation;
+mod slices;
+mod sound;
+mod tilemap;
+mod triggers;
+
+use crate::abilities::AbilitiesPlugin;
 // use crate::animated_sprite::AnimatedSpritePlugin;
+use crate::aseprite::AsepritePlugin;
+use crate::asset_check::{AssetCheckPlugin, LoadingState};
+use crate::atlas::AtlasPlugin;
+use crate::autotile::AutoTilePlugin;
+use crate::character_controller::CharacterControllerPlugin;
+use crate::checkpoints::CheckpointsPlugin;
+use crate::collision::CollisionPlugin;
+use crate::combat::{CombatPlugin, HitStun};
 use crate::custom_parallax::CustomParallaxPlugin;
+use crate::doors::DoorsPlugin;
+use crate::equipment::{EquipmentPlugin, Grip};
 use crate::hello::HelloPlugin;
+use crate::ldtk::LdtkPlugin;
+use crate::level_entities::LevelEntitiesPlugin;
+use crate::level_streaming::LevelStreamingPlugin;
+use crate::prefab::{PrefabPlugin, SpawnPrefabExt};
+use crate::render_layers::{DrawOrder, RenderLayersPlugin};
+use crate::simulation::{Position, SimulationPlugin};
+use crate::slices::SlicesPlugin;
+use crate::sound::SoundPlugin;
+use crate::tilemap::TilemapPlugin;
+use crate::triggers::TriggersPlugin;
 
 fn main() {
     let window = WindowDescriptor {
@@ -32,8 +78,29 @@ fn main() {
         .insert_resource(window)
         .add_plugins(DefaultPlugins)
         //.add_plugin(LdtkPlugin)
+        .add_plugin(AssetCheckPlugin)
         .add_plugin(CustomParallaxPlugin)
         .add_plugin(HelloPlugin)
+        .add_plugin(SimulationPlugin)
+        .add_plugin(CollisionPlugin)
+        .add_plugin(CharacterControllerPlugin)
+        .add_plugin(AbilitiesPlugin)
+        .add_plugin(CombatPlugin)
+        .add_plugin(AtlasPlugin)
+        .add_plugin(AsepritePlugin)
+        .add_plugin(SlicesPlugin)
+        .add_plugin(EquipmentPlugin)
+        .add_plugin(RenderLayersPlugin)
+        .add_plugin(LdtkPlugin)
+        .add_plugin(TilemapPlugin)
+        .add_plugin(AutoTilePlugin)
+        .add_plugin(LevelStreamingPlugin)
+        .add_plugin(LevelEntitiesPlugin)
+        .add_plugin(DoorsPlugin)
+        .add_plugin(CheckpointsPlugin)
+        .add_plugin(SoundPlugin)
+        .add_plugin(TriggersPlugin)
+        .add_plugin(PrefabPlugin)
         .add_plugin(CharacterPlugin)
         //.add_plugin(AnimatedSpritePlugin)
         .run();
@@ -45,19 +112,11 @@ struct Life(u8);
 #[derive(Component)]
 struct Player;
 
-#[derive(Bundle)]
-struct PlayerBundle {
-    life: Life,
-    is_player: Player,
-    #[bundle]
-    entity_bundle: EntityBundle,
-}
+#[derive(Component)]
+struct Enemy;
 
 #[derive(Component)]
-struct Position {
-    x: f32,
-    y: f32,
-}
+struct Projectile;
 
 #[derive(Component, Deref)]
 struct SpriteSheetURL(String);
@@ -68,29 +127,26 @@ struct EntityBundle {
     #[bundle]
     sprite_sheet_bundle: SpriteSheetBundle,
     position: Position,
+    draw_order: DrawOrder,
 }
 
 impl EntityBundle {
-    pub fn from_asset(
+    pub fn new(
         url: String,
-        asset_server: Res<AssetServer>,
-        mut texture_atlas: ResMut<Assets<TextureAtlas>>,
-        columns: usize,
-        rows: usize,
+        texture_atlas: Handle<TextureAtlas>,
+        position: Vec2,
+        draw_order: DrawOrder,
     ) -> Self {
-        let texture_handle = asset_server.load(&url);
-        let _texture_atlas =
-            TextureAtlas::from_grid(texture_handle, Vec2::new(67.0, 78.0), columns, rows);
-        let texture_atlas_handle = texture_atlas.add(_texture_atlas);
-
         EntityBundle {
-            sheet_url: SpriteSheetURL(url.to_string()),
+            sheet_url: SpriteSheetURL(url),
             sprite_sheet_bundle: SpriteSheetBundle {
-                texture_atlas: texture_atlas_handle,
-                transform: Transform::from_xyz(100.0, 100.0, 100.0),
+                texture_atlas,
+                // x/y are synced from `position` every frame
+                transform: Transform::from_translation(position.extend(draw_order.z())),
                 ..Default::default()
             },
-            position: Position { x: 100.0, y: 100.0 },
+            position: Position::new(position),
+            draw_order,
         }
     }
 }
@@ -99,7 +155,7 @@ struct CharacterPlugin;
 
 impl Plugin for CharacterPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_entity)
+        app.add_system_set(SystemSet::on_enter(LoadingState::Ready).with_system(setup_entity))
             .add_state(BaseEntityStates::Idle)
             .add_system(update_entity_frame);
     }
@@ -108,86 +164,42 @@ impl Plugin for CharacterPlugin {
 #[derive(Component, Deref, DerefMut)]
 struct AnimationTimer(Timer);
 
-fn setup_entity(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    texture_atlas: ResMut<Assets<TextureAtlas>>,
-) {
-    let animation_states: Vec<AnimationState> = vec![
-        AnimationState {
-            animation: Animation {
-                name: "idle".to_string(),
-                index_difference: 3,
-                index_start: 56,
-            },
-            state: Some(BaseEntityStates::Idle),
-        },
-        AnimationState {
-            animation: Animation {
-                name: "walk".to_string(),
-                index_difference: 4,
-                index_start: 112,
-            },
-            state: Some(BaseEntityStates::Walking),
-        },
-        AnimationState {
-            animation: Animation {
-                name: "attack".to_string(),
-                index_difference: 5,
-                index_start: 21,
-            },
-            state: Some(BaseEntityStates::Attack),
-        },
-        AnimationState {
-            animation: Animation {
-                name: "on-hit".to_string(),
-                index_difference: 3,
-                index_start: 0,
-            },
-            state: Some(BaseEntityStates::OnHit),
-        },
-        AnimationState {
-            animation: Animation {
-                name: "death".to_string(),
-                index_difference: 1,
-                index_start: 31,
-            },
-            state: Some(BaseEntityStates::Death),
-        },
-    ];
-
-    let _entity = commands
-        .spawn_bundle(EntityBundle::from_asset(
-            "MaplestoryDefaultSpriteSheet/maple-default.png".to_string(),
-            asset_server,
-            texture_atlas,
-            10,
-            13,
-        ))
-        .insert(EntityAnimations::setup(
-            animation_states,
-            Some(BaseEntityStates::Idle),
-        ))
-        .insert(AnimationTimer(Timer::from_seconds(0.4, true)))
-        .insert(StateChangeTimer(Timer::from_seconds(2.0, true)))
-        //.add_system()
-        .id();
+fn setup_entity(mut commands: Commands) {
+    commands.spawn_prefab("player", Vec2::new(100.0, 100.0));
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, PartialOrd, Ord)]
 struct Animation {
     name: String,
-    index_difference: usize,
-    index_start: usize,
+    // atlas indices in play order
+    frames: Vec<usize>,
+    // milliseconds each frame shows, from Aseprite files; empty uses the prefab's frame time
+    durations: Vec<u32>,
+}
+
+impl Animation {
+    fn from_range(name: String, index_start: usize, index_difference: usize) -> Self {
+        Animation {
+            name,
+            frames: (index_start..=index_start + index_difference).collect(),
+            durations: Vec::new(),
+        }
+    }
+
+    fn first_index(&self) -> usize {
+        self.frames.first().copied().unwrap_or(0)
+    }
 }
 
 #[derive(Clone, PartialEq)]
 struct AnimationState {
     animation: Animation,
     state: Option<BaseEntityStates>,
+    // variant used only while holding a weapon with this grip, e.g. the 2H idle
+    grip: Option<Grip>,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Copy)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Copy, Deserialize)]
 enum BaseEntityStates {
     Idle,
     Walking,
@@ -198,9 +210,14 @@ enum BaseEntityStates {
 
 #[derive(Component)]
 struct EntityAnimations {
+    // atlas index of the frame shown, and its position in the current animation
     current_index: usize,
+    current_frame: usize,
     animation_states: Vec<AnimationState>,
     current_state: AnimationState,
+    grip: Grip,
+    // the state changed since the sprite was last updated
+    restarted: bool,
 }
 
 impl EntityAnimations {
@@ -216,13 +233,18 @@ impl EntityAnimations {
         let _current_state = EntityAnimations::static_find_animation_state_by_state(
             animation_states.clone(),
             current_state.unwrap(),
+            Grip::default(),
         )
         .unwrap();
 
         let mut entity_animations = EntityAnimations {
-            current_index: _current_state.animation.index_start,
+            current_index: _current_state.animation.first_index(),
+            current_frame: 0,
             animation_states: animation_states.clone(),
             current_state: _current_state,
+            grip: Grip::default(),
+            // shows the first frame right away
+            restarted: true,
         };
         entity_animations.sort_animations();
         entity_animations.initialize_animation();
@@ -236,19 +258,49 @@ impl EntityAnimations {
             self.initialize_animation();
         }
 
-        self.current_index += 1;
-        if self.current_index
-            > (current_state.animation.index_start + current_state.animation.index_difference)
-        {
+        self.current_frame += 1;
+        if self.current_frame >= current_state.animation.frames.len() {
             let current_state = self
                 .current_state
                 .state
                 .as_ref()
                 .unwrap_or(&BaseEntityStates::Idle);
-            let new_animation = &self
+            let new_animation = self
                 .find_animation_state_by_state(current_state.clone())
                 .unwrap();
-            self.current_index = new_animation.animation.index_start;
+            self.play(new_animation);
+        } else {
+            self.current_index = current_state.animation.frames[self.current_frame];
+        }
+    }
+
+    /// How long the current frame shows, when the animation has its own frame durations.
+    pub fn frame_duration(&self) -> Option<Duration> {
+        self.current_state
+            .animation
+            .durations
+            .get(self.current_frame)
+            .map(|milliseconds| Duration::from_millis(*milliseconds as u64))
+    }
+
+    fn play(&mut self, animation_state: AnimationState) {
+        self.current_frame = 0;
+        self.current_index = animation_state.animation.first_index();
+        self.current_state = animation_state;
+    }
+
+    /// Switches to the variants for `grip`, restarting the current animation if it changes.
+    pub fn set_grip(&mut self, grip: Grip) {
+        if self.grip == grip {
+            return;
+        }
+        self.grip = grip;
+        let state = self.current_state.state.unwrap_or(BaseEntityStates::Idle);
+        if let Some(animation_state) = self.find_animation_state_by_state(state) {
+            if animation_state.animation != self.current_state.animation {
+                self.play(animation_state);
+                self.restarted = true;
+            }
         }
     }
 
@@ -257,36 +309,50 @@ impl EntityAnimations {
         if current_state.state.is_none() {
             self.current_state.state = Some(BaseEntityStates::Idle);
             current_state = &self.current_state;
-            self.current_index = current_state.animation.index_start;
+            self.current_index = current_state.animation.first_index();
+            self.current_frame = 0;
         }
     }
 
     fn find_animation_state_by_state(&self, state: BaseEntityStates) -> Option<AnimationState> {
-        let animations = &self.animation_states;
-        let mut result: Option<AnimationState> = None;
-        for animation_state in animations.into_iter() {
-            if animation_state.state == Some(state.clone()) {
-                result = Some(animation_state.clone());
-            }
-        }
-        result
+        EntityAnimations::static_find_animation_state_by_state(
+            self.animation_states.clone(),
+            state,
+            self.grip,
+        )
     }
 
+    // prefers the variant for `grip`, falling back to the one without a grip
     pub fn static_find_animation_state_by_state(
         animation_states: Vec<AnimationState>,
         state: BaseEntityStates,
+        grip: Grip,
     ) -> Option<AnimationState> {
         let mut result: Option<AnimationState> = None;
         for animation_state in animation_states.into_iter() {
-            if animation_state.state == Some(state.clone()) {
+            if animation_state.state != Some(state.clone()) {
+                continue;
+            }
+            if animation_state.grip == Some(grip) {
+                return Some(animation_state);
+            }
+            if animation_state.grip.is_none() {
                 result = Some(animation_state.clone());
             }
         }
         result
     }
 
+    /// Switches to the animation of `new_entity_state` right away, from its first frame.
     pub fn update_state(&mut self, new_entity_state: BaseEntityStates) {
+        if self.current_state.state == Some(new_entity_state) {
+            return;
+        }
         self.current_state.state = Some(new_entity_state);
+        if let Some(animation_state) = self.find_animation_state_by_state(new_entity_state) {
+            self.play(animation_state);
+            self.restarted = true;
+        }
     }
 
     pub fn insert_animation(
@@ -296,14 +362,11 @@ impl EntityAnimations {
         index_start: usize,
         state: BaseEntityStates,
     ) {
-        let animation = Animation {
-            name: name,
-            index_difference: index_difference,
-            index_start: index_start,
-        };
+        let animation = Animation::from_range(name, index_start, index_difference);
         self.animation_states.push(AnimationState {
             state: Some(state),
             animation: animation,
+            grip: None,
         });
         self.sort_animations();
     }
@@ -311,7 +374,7 @@ impl EntityAnimations {
     fn sort_animations(&mut self) {
         if &self.animation_states.len() > &1 {
             self.animation_states
-                .sort_by(|a, b| a.animation.index_start.cmp(&b.animation.index_start));
+                .sort_by_key(|animation_state| animation_state.animation.first_index());
         }
     }
 }
@@ -327,18 +390,35 @@ fn update_entity_frame(
         &mut AnimationTimer,
         &mut TextureAtlasSprite,
         &mut EntityAnimations,
-        &mut StateChangeTimer,
+        Option<&mut StateChangeTimer>,
+        Option<&HitStun>,
+        Option<&Life>,
         &Handle<TextureAtlas>,
     )>,
 ) {
-    for (mut timer, mut sprite, mut entity_animations, mut state_timer, texture_atlas_handle) in
-        query.iter_mut()
+    for (
+        mut timer,
+        mut sprite,
+        mut entity_animations,
+        state_timer,
+        hit_stun,
+        life,
+        texture_atlas_handle,
+    ) in query.iter_mut()
     {
+        // a new state shows its first frame now, not when the current frame ends
+        if entity_animations.restarted {
+            entity_animations.restarted = false;
+            sprite.index = entity_animations.current_index;
+            timer.reset();
+            if let Some(duration) = entity_animations.frame_duration() {
+                timer.set_duration(duration);
+            }
+        }
         timer.tick(time.delta());
         if timer.just_finished() {
             let _texture_atlas = texture_atlas.get(texture_atlas_handle).unwrap();
 
-            let new_index = entity_animations.current_index;
             entity_animations.update_animation();
             if entity_animations
                 .current_state
@@ -350,9 +430,18 @@ fn update_entity_frame(
                 entity_animations.update_state(_state.current().clone());
             }
 
-            sprite.index = new_index;
+            sprite.index = entity_animations.current_index;
+            if let Some(duration) = entity_animations.frame_duration() {
+                timer.set_duration(duration);
+            }
         }
 
+        // hit reactions and death are left to play out
+        let mut state_timer = match state_timer {
+            Some(_) if hit_stun.is_some() || life.map_or(false, |life| **life == 0) => continue,
+            Some(state_timer) => state_timer,
+            None => continue,
+        };
         state_timer.tick(time.delta());
         if state_timer.just_finished() {
             match _state.current() {
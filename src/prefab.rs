@@ -0,0 +1,491 @@
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadState, LoadedAsset};
+use bevy::ecs::system::EntityCommands;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+
+use crate::abilities::{Abilities, Ability};
+use crate::aseprite::Aseprite;
+use crate::atlas::{
+    grid_atlas, sheet_atlas, validate_animation, validate_grid, validate_sheet, AtlasGrid,
+    SpriteSheetMeta,
+};
+use crate::character_controller::CharacterController;
+use crate::collision::Collider;
+use crate::equipment::{Equipment, EquipmentItem, Grip};
+use crate::ldtk_fields::FieldValue;
+use crate::level_entities::RegisterLdtkEntityExt;
+use crate::render_layers::{DrawOrder, RenderLayer, YSort};
+use crate::slices::{FrameAnchors, FrameSlices};
+use crate::{
+    Animation, AnimationState, AnimationTimer, BaseEntityStates, Enemy, EntityAnimations,
+    EntityBundle, Life, Player, Projectile, StateChangeTimer,
+};
+
+pub struct PrefabPlugin;
+
+impl Plugin for PrefabPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Prefab>()
+            .init_asset_loader::<PrefabLoader>()
+            .init_resource::<PrefabLibrary>()
+            .add_system(instantiate_prefabs)
+            // any prefab can be placed in LDtk as a "Prefab" entity naming it
+            .register_ldtk_entity("Prefab", |entity, instance| {
+                match instance.field("prefab") {
+                    Some(FieldValue::String(name)) => {
+                        entity.insert(PendingPrefab {
+                            name: name.clone(),
+                            position: instance.center(),
+                        });
+                    }
+                    _ => warn!(
+                        "{}: Prefab entity {} has no \"prefab\" field",
+                        instance.level, instance.iid
+                    ),
+                }
+            });
+    }
+}
+
+/* Prefab files live in `assets/prefabs/<name>.prefab.ron` and describe everything
+needed to spawn an entity: sprite sheet, animations, stats, collider and behaviours.
+The sprites come either from an image (`sprite_sheet`, cut by its grid or metadata) or
+straight from an Aseprite file (`aseprite`), whose animations can name the file's tags
+and play with its frame durations.
+ */
+#[derive(Deserialize, TypeUuid)]
+#[uuid = "5b0f2a9c-3f0e-4d55-9a41-6f1c2e8d7b10"]
+pub struct Prefab {
+    #[serde(default)]
+    pub sprite_sheet: String,
+    #[serde(default)]
+    pub aseprite: Option<String>,
+    // Aseprite JSON exported with the sheet (`.sheet.json`), used to validate the grid
+    #[serde(default)]
+    pub metadata: Option<String>,
+    // grid of the sheet; leave out for packed atlases, which use the metadata frame rects
+    #[serde(default)]
+    pub cell_size: (f32, f32),
+    #[serde(default)]
+    pub columns: usize,
+    #[serde(default)]
+    pub rows: usize,
+    #[serde(default)]
+    pub layer: RenderLayer,
+    // order inside the layer
+    #[serde(default)]
+    pub z: f32,
+    // draw lower instances in front of higher ones
+    #[serde(default)]
+    pub y_sort: bool,
+    #[serde(default = "default_frame_time")]
+    pub frame_time: f32,
+    #[serde(default)]
+    pub initial_state: Option<BaseEntityStates>,
+    #[serde(default)]
+    pub animations: Vec<PrefabAnimation>,
+    #[serde(default)]
+    pub speed: Option<f32>,
+    #[serde(default)]
+    pub life: Option<u8>,
+    // half size of the collision box
+    #[serde(default)]
+    pub collider: Option<(f32, f32)>,
+    #[serde(default)]
+    pub behaviours: Vec<Behaviour>,
+}
+
+impl Prefab {
+    pub fn asset_paths(&self) -> Vec<&str> {
+        let mut paths = Vec::new();
+        match &self.aseprite {
+            Some(aseprite) => paths.push(aseprite.as_str()),
+            None => paths.push(self.sprite_sheet.as_str()),
+        }
+        paths.extend(self.metadata.as_deref());
+        for behaviour in self.behaviours.iter() {
+            if let Behaviour::Equipment(items) = behaviour {
+                paths.extend(items.iter().map(|item| item.sheet.as_str()));
+            }
+        }
+        paths
+    }
+}
+
+fn default_frame_time() -> f32 {
+    0.2
+}
+
+#[derive(Deserialize)]
+pub struct PrefabAnimation {
+    pub name: String,
+    pub state: BaseEntityStates,
+    // Aseprite tag to play, in place of the index range
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub index_start: usize,
+    #[serde(default)]
+    pub index_difference: usize,
+    #[serde(default)]
+    pub grip: Option<Grip>,
+}
+
+#[derive(Deserialize)]
+pub enum Behaviour {
+    Player,
+    Enemy,
+    Projectile,
+    CharacterController,
+    Abilities(Vec<Ability>),
+    Equipment(Vec<EquipmentItem>),
+    // cycles through the animation states every n seconds, for showcasing sheets
+    CycleStates(f32),
+}
+
+#[derive(Default)]
+pub struct PrefabLoader;
+
+impl AssetLoader for PrefabLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let prefab: Prefab = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(prefab));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["prefab.ron"]
+    }
+}
+
+/// Prefab handles by name, plus the texture atlas built for each so instances share it.
+#[derive(Default)]
+pub struct PrefabLibrary {
+    prefabs: HashMap<String, Handle<Prefab>>,
+    sources: HashMap<String, (Handle<Image>, Option<Handle<SpriteSheetMeta>>)>,
+    aseprites: HashMap<String, Handle<Aseprite>>,
+    // atlas and its usable frame count
+    atlases: HashMap<String, (Handle<TextureAtlas>, usize)>,
+}
+
+impl PrefabLibrary {
+    pub fn handle(&mut self, name: &str, asset_server: &AssetServer) -> Handle<Prefab> {
+        self.prefabs
+            .entry(name.to_string())
+            .or_insert_with(|| asset_server.load(&format!("prefabs/{}.prefab.ron", name)))
+            .clone()
+    }
+}
+
+/// Waiting for its prefab to load; replaced by the prefab's components once it has.
+#[derive(Component)]
+pub struct PendingPrefab {
+    pub name: String,
+    pub position: Vec2,
+}
+
+pub trait SpawnPrefabExt<'w, 's> {
+    fn spawn_prefab<'a>(&'a mut self, name: &str, position: Vec2) -> EntityCommands<'w, 's, 'a>;
+}
+
+impl<'w, 's> SpawnPrefabExt<'w, 's> for Commands<'w, 's> {
+    fn spawn_prefab<'a>(&'a mut self, name: &str, position: Vec2) -> EntityCommands<'w, 's, 'a> {
+        let mut entity = self.spawn();
+        entity.insert(PendingPrefab {
+            name: name.to_string(),
+            position,
+        });
+        entity
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn instantiate_prefabs(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    prefabs: Res<Assets<Prefab>>,
+    images: Res<Assets<Image>>,
+    sheet_metas: Res<Assets<SpriteSheetMeta>>,
+    aseprites: Res<Assets<Aseprite>>,
+    mut library: ResMut<PrefabLibrary>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    query: Query<(Entity, &PendingPrefab)>,
+) {
+    for (entity, pending) in query.iter() {
+        let handle = library.handle(&pending.name, &asset_server);
+        let prefab = match prefabs.get(&handle) {
+            Some(prefab) => prefab,
+            None => {
+                if asset_server.get_load_state(&handle) == LoadState::Failed {
+                    warn!("could not load prefab \"{}\"", pending.name);
+                    commands.entity(entity).despawn();
+                }
+                continue;
+            }
+        };
+
+        if let Some(path) = &prefab.aseprite {
+            let handle = library
+                .aseprites
+                .entry(pending.name.clone())
+                .or_insert_with(|| asset_server.load(path))
+                .clone();
+            let ase = match aseprites.get(&handle) {
+                Some(ase) => ase,
+                None => {
+                    if asset_server.get_load_state(&handle) == LoadState::Failed {
+                        error!("prefab \"{}\": could not load {}", pending.name, path);
+                        commands.entity(entity).despawn();
+                    }
+                    continue;
+                }
+            };
+            let mut entity_commands = commands.entity(entity);
+            entity_commands.insert_bundle(EntityBundle::new(
+                path.clone(),
+                ase.atlas.clone(),
+                pending.position,
+                DrawOrder {
+                    layer: prefab.layer,
+                    offset: prefab.z,
+                },
+            ));
+            let slices = FrameSlices::from_aseprite(ase);
+            if !slices.is_empty() {
+                entity_commands.insert(slices);
+            }
+            insert_animations(
+                &mut entity_commands,
+                &pending.name,
+                prefab,
+                Some(ase),
+                ase.frames.len(),
+            );
+            insert_components(&mut entity_commands, prefab);
+            continue;
+        }
+
+        let (texture, metadata) = library
+            .sources
+            .entry(pending.name.clone())
+            .or_insert_with(|| {
+                (
+                    asset_server.load(&prefab.sprite_sheet),
+                    prefab.metadata.as_ref().map(|path| asset_server.load(path)),
+                )
+            })
+            .clone();
+        let image = match images.get(&texture) {
+            Some(image) => image,
+            None => {
+                if asset_server.get_load_state(&texture) == LoadState::Failed {
+                    error!(
+                        "prefab \"{}\": could not load {}",
+                        pending.name, prefab.sprite_sheet
+                    );
+                    commands.entity(entity).despawn();
+                }
+                continue;
+            }
+        };
+        let meta = match &metadata {
+            Some(metadata) => match sheet_metas.get(metadata) {
+                Some(meta) => Some(meta),
+                None => {
+                    if asset_server.get_load_state(metadata) == LoadState::Failed {
+                        error!(
+                            "prefab \"{}\": could not load its sheet metadata",
+                            pending.name
+                        );
+                        commands.entity(entity).despawn();
+                    }
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        if !library.atlases.contains_key(&pending.name) {
+            let grid = AtlasGrid {
+                cell_size: Vec2::new(prefab.cell_size.0, prefab.cell_size.1),
+                columns: prefab.columns,
+                rows: prefab.rows,
+            };
+            // trimmed or packed frames are laid out at their own size, so there is no grid to check
+            let packed = meta.filter(|meta| {
+                prefab.columns == 0 || meta.frames.iter().any(|frame| frame.trimmed)
+            });
+            let atlas = match packed {
+                Some(meta) => validate_sheet(image.size(), meta)
+                    .map(|frames| (sheet_atlas(texture.clone(), image.size(), meta), frames)),
+                None => validate_grid(&grid, image.size(), meta)
+                    .map(|frames| (grid_atlas(texture.clone(), &grid, frames), frames)),
+            };
+            match atlas {
+                Ok((atlas, frames)) => {
+                    let atlas = texture_atlases.add(atlas);
+                    library
+                        .atlases
+                        .insert(pending.name.clone(), (atlas, frames));
+                }
+                Err(errors) => {
+                    for error in errors {
+                        error!("prefab \"{}\": {}", pending.name, error);
+                    }
+                    commands.entity(entity).despawn();
+                    continue;
+                }
+            }
+        }
+        let (texture_atlas, frames) = library.atlases[&pending.name].clone();
+
+        let mut entity_commands = commands.entity(entity);
+        entity_commands.insert_bundle(EntityBundle::new(
+            prefab.sprite_sheet.clone(),
+            texture_atlas,
+            pending.position,
+            DrawOrder {
+                layer: prefab.layer,
+                offset: prefab.z,
+            },
+        ));
+        insert_animations(&mut entity_commands, &pending.name, prefab, None, frames);
+
+        if let Some(meta) = meta {
+            let slices = FrameSlices::from_sheet(meta);
+            if !slices.is_empty() {
+                entity_commands.insert(slices);
+            }
+            if let Some(anchors) = FrameAnchors::from_sheet(meta) {
+                entity_commands.insert(anchors);
+            }
+        }
+
+        insert_components(&mut entity_commands, prefab);
+    }
+}
+
+// the animation states, played from the Aseprite file's tags and durations when there is one
+fn insert_animations(
+    entity_commands: &mut EntityCommands,
+    name: &str,
+    prefab: &Prefab,
+    ase: Option<&Aseprite>,
+    frames: usize,
+) {
+    entity_commands.insert(AnimationTimer(Timer::from_seconds(prefab.frame_time, true)));
+
+    let mut animation_states: Vec<AnimationState> = Vec::new();
+    for animation in prefab.animations.iter() {
+        let sequence = match (&animation.tag, ase) {
+            (Some(tag), Some(ase)) => match ase.tag(tag) {
+                Some(tag) => tag.frame_sequence(),
+                None => {
+                    error!(
+                        "prefab \"{}\": animation \"{}\" plays tag \"{}\", which the file does not have",
+                        name, animation.name, tag
+                    );
+                    continue;
+                }
+            },
+            (Some(_), None) => {
+                error!(
+                    "prefab \"{}\": animation \"{}\" names a tag, but the prefab has no Aseprite file",
+                    name, animation.name
+                );
+                continue;
+            }
+            (None, _) => (animation.index_start
+                ..=animation.index_start + animation.index_difference)
+                .collect(),
+        };
+        let (first, last) = match (sequence.iter().min(), sequence.iter().max()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => continue,
+        };
+        if let Err(error) = validate_animation(&animation.name, first, last, frames) {
+            error!("prefab \"{}\": {}", name, error);
+            continue;
+        }
+        let durations = match ase {
+            Some(ase) => sequence
+                .iter()
+                .map(|index| (ase.frames[*index].duration * 1000.0).round() as u32)
+                .collect(),
+            None => Vec::new(),
+        };
+        animation_states.push(AnimationState {
+            animation: Animation {
+                name: animation.name.clone(),
+                frames: sequence,
+                durations,
+            },
+            state: Some(animation.state),
+            grip: animation.grip,
+        });
+    }
+    if let Some(first) = animation_states.first() {
+        let initial_state = prefab
+            .initial_state
+            .filter(|state| {
+                animation_states
+                    .iter()
+                    .any(|animation_state| animation_state.state == Some(*state))
+            })
+            .or(first.state);
+        entity_commands.insert(EntityAnimations::setup(animation_states, initial_state));
+    }
+}
+
+fn insert_components(entity_commands: &mut EntityCommands, prefab: &Prefab) {
+    entity_commands.remove::<PendingPrefab>();
+    if prefab.y_sort {
+        entity_commands.insert(YSort);
+    }
+    if let Some(life) = prefab.life {
+        entity_commands.insert(Life(life));
+    }
+    if let Some((x, y)) = prefab.collider {
+        entity_commands.insert(Collider {
+            half_size: Vec2::new(x, y),
+        });
+    }
+
+    for behaviour in prefab.behaviours.iter() {
+        match behaviour {
+            Behaviour::Player => {
+                entity_commands.insert(Player);
+            }
+            Behaviour::Enemy => {
+                entity_commands.insert(Enemy);
+            }
+            Behaviour::Projectile => {
+                entity_commands.insert(Projectile);
+            }
+            Behaviour::CharacterController => {
+                let mut controller = CharacterController::default();
+                if let Some(speed) = prefab.speed {
+                    controller.speed = speed;
+                }
+                entity_commands.insert(controller);
+            }
+            Behaviour::Abilities(abilities) => {
+                entity_commands.insert(Abilities::with(abilities));
+            }
+            Behaviour::Equipment(items) => {
+                entity_commands.insert(Equipment::with(items));
+            }
+            Behaviour::CycleStates(seconds) => {
+                entity_commands.insert(StateChangeTimer(Timer::from_seconds(*seconds, true)));
+            }
+        }
+    }
+}
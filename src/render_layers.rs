@@ -0,0 +1,93 @@
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use serde::Deserialize;
+
+use crate::simulation::sync_transforms;
+
+pub struct RenderLayersPlugin;
+
+impl Plugin for RenderLayersPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            apply_draw_order
+                .after(sync_transforms)
+                .before(TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+/// Width of the z range each layer owns.
+pub const LAYER_DEPTH: f32 = 100.0;
+/// Depth per world unit of height for y-sorted sprites; children (e.g. equipment layers)
+/// should stay within this of their parent to keep their order.
+pub const Y_SORT_STEP: f32 = 0.01;
+
+/* Named render layers, back to front. Each owns `LAYER_DEPTH` of z starting at
+`index * LAYER_DEPTH`, well below the 2D camera's far plane (1000).
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+pub enum RenderLayer {
+    BackgroundParallax,
+    LevelBack,
+    Entities,
+    LevelFront,
+    Effects,
+    ForegroundParallax,
+    Ui,
+}
+
+impl Default for RenderLayer {
+    fn default() -> Self {
+        RenderLayer::Entities
+    }
+}
+
+impl RenderLayer {
+    pub fn z_range(&self) -> (f32, f32) {
+        let min = *self as usize as f32 * LAYER_DEPTH;
+        (min, min + LAYER_DEPTH)
+    }
+
+    /// The z `offset` into the layer, kept inside it.
+    pub fn z(&self, offset: f32) -> f32 {
+        let (min, max) = self.z_range();
+        (min + offset).clamp(min, max - 1.0)
+    }
+}
+
+/// Places the entity in a render layer instead of using a hand-picked z.
+#[derive(Component, Clone, Copy, Default)]
+pub struct DrawOrder {
+    pub layer: RenderLayer,
+    // order inside the layer
+    pub offset: f32,
+}
+
+impl DrawOrder {
+    pub fn new(layer: RenderLayer) -> Self {
+        DrawOrder { layer, offset: 0.0 }
+    }
+
+    pub fn z(&self) -> f32 {
+        self.layer.z(self.offset)
+    }
+}
+
+/// Draws lower sprites in front of higher ones, within the middle of the entity's layer.
+#[derive(Component)]
+pub struct YSort;
+
+pub fn apply_draw_order(mut query: Query<(&DrawOrder, Option<&YSort>, &mut Transform)>) {
+    for (draw_order, y_sort, mut transform) in query.iter_mut() {
+        let z = match y_sort {
+            Some(_) => draw_order
+                .layer
+                .z(LAYER_DEPTH / 2.0 + draw_order.offset - transform.translation.y * Y_SORT_STEP),
+            None => draw_order.z(),
+        };
+        if transform.translation.z != z {
+            transform.translation.z = z;
+        }
+    }
+}
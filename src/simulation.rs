@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use bevy::core::{FixedTimestep, FixedTimesteps};
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+
+/// Gameplay movement and collision run at this fixed rate, independent of the frame rate.
+pub const SIMULATION_TIMESTEP: f64 = 1.0 / 60.0;
+
+const SIMULATION_TIMESTEP_LABEL: &str = "simulation";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub struct SimulationStage;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct StorePreviousPositions;
+
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_stage_after(
+            CoreStage::Update,
+            SimulationStage,
+            SystemStage::parallel().with_run_criteria(
+                FixedTimestep::step(SIMULATION_TIMESTEP).with_label(SIMULATION_TIMESTEP_LABEL),
+            ),
+        )
+        .add_system_to_stage(
+            SimulationStage,
+            store_previous_positions.label(StorePreviousPositions),
+        )
+        .init_resource::<PixelSnap>()
+        .add_system_to_stage(
+            CoreStage::PostUpdate,
+            sync_transforms.before(TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+pub fn simulation_delta() -> Duration {
+    Duration::from_secs_f64(SIMULATION_TIMESTEP)
+}
+
+pub fn simulation_delta_seconds() -> f32 {
+    SIMULATION_TIMESTEP as f32
+}
+
+/// The authoritative, sub-pixel position of an entity and its velocity in pixels per second.
+/// Only the simulation writes it; the rendered `Transform` is synced from it every frame,
+/// interpolated between the two last simulated positions.
+#[derive(Component, Clone, Copy, Default)]
+pub struct Position {
+    pub previous: Vec2,
+    pub current: Vec2,
+    pub velocity: Vec2,
+}
+
+impl Position {
+    pub fn new(position: Vec2) -> Self {
+        Position {
+            previous: position,
+            current: position,
+            velocity: Vec2::ZERO,
+        }
+    }
+
+    // moves without interpolating from the old position
+    pub fn teleport(&mut self, position: Vec2) {
+        self.previous = position;
+        self.current = position;
+    }
+
+    // where to draw the entity, `alpha` of the way from the previous step to the current one
+    pub fn interpolated(&self, alpha: f32) -> Vec2 {
+        self.previous.lerp(self.current, alpha.clamp(0.0, 1.0))
+    }
+}
+
+/// How synced transforms are rounded. Physics always keeps the fractional position.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PixelSnap {
+    Off,
+    // snap to multiples of this many world units, e.g. 2.0 for sprites scaled up twice
+    Grid(f32),
+}
+
+impl Default for PixelSnap {
+    fn default() -> Self {
+        PixelSnap::Grid(1.0)
+    }
+}
+
+impl PixelSnap {
+    pub fn apply(&self, position: Vec2) -> Vec2 {
+        match *self {
+            PixelSnap::Grid(size) if size > 0.0 => (position / size).round() * size,
+            _ => position,
+        }
+    }
+}
+
+fn store_previous_positions(mut query: Query<&mut Position>) {
+    for mut position in query.iter_mut() {
+        position.previous = position.current;
+    }
+}
+
+pub fn sync_transforms(
+    fixed_timesteps: Res<FixedTimesteps>,
+    pixel_snap: Res<PixelSnap>,
+    mut query: Query<(&Position, &mut Transform)>,
+) {
+    let alpha = fixed_timesteps
+        .get(SIMULATION_TIMESTEP_LABEL)
+        .map_or(1.0, |state| state.overstep_percentage() as f32);
+
+    for (position, mut transform) in query.iter_mut() {
+        let interpolated = pixel_snap.apply(position.interpolated(alpha));
+        transform.translation.x = interpolated.x;
+        transform.translation.y = interpolated.y;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::core::CorePlugin;
+
+    use super::*;
+    use crate::character_controller::{move_characters, CharacterController};
+    use crate::collision::{Collider, CollisionGrid, SurfaceMaterials};
+
+    // where the character was after each simulation step
+    #[derive(Default)]
+    struct Steps(Vec<Position>);
+
+    fn record_step(mut steps: ResMut<Steps>, query: Query<&Position>) {
+        steps.0.extend(query.iter().copied());
+    }
+
+    // runs a character walking off a ledge through the real simulation stage for `count`
+    // steps, with frames `frame_time` apart. `Time` only follows the wall clock in Bevy
+    // 0.7, so the frames are really that long.
+    fn simulate(frame_time: f64, count: usize) -> Vec<Position> {
+        let mut app = App::new();
+        let csv = "0,0,0,0,0,0\n0,0,0,0,0,0\n1,1,1,0,0,0\n1,1,1,1,1,1\n";
+        app.add_plugin(CorePlugin)
+            .add_plugin(SimulationPlugin)
+            .insert_resource(CollisionGrid::from_csv(csv, 16.0, Vec2::new(-48.0, 32.0)))
+            .init_resource::<SurfaceMaterials>()
+            .init_resource::<Steps>()
+            .add_system_to_stage(
+                SimulationStage,
+                move_characters.label("move").after(StorePreviousPositions),
+            )
+            .add_system_to_stage(SimulationStage, record_step.after("move"));
+        app.world
+            .spawn()
+            .insert(Position::new(Vec2::new(-40.0, 20.0)))
+            .insert(Transform::default())
+            .insert(CharacterController {
+                speed: 60.0,
+                move_direction: 1.0,
+                ..Default::default()
+            })
+            .insert(Collider {
+                half_size: Vec2::new(4.0, 4.0),
+            });
+
+        // the first update only starts the clock
+        app.update();
+        while app.world.resource::<Steps>().0.len() < count {
+            std::thread::sleep(Duration::from_secs_f64(frame_time));
+            app.update();
+
+            // drawn between the last two steps
+            let mut query = app.world.query::<(&Position, &Transform)>();
+            let (position, transform) = query.iter(&app.world).next().unwrap();
+            let drawn = transform.translation.truncate();
+            let (low, high) = (
+                position.previous.min(position.current).round(),
+                position.previous.max(position.current).round(),
+            );
+            assert!(drawn.cmpge(low).all() && drawn.cmple(high).all());
+        }
+        app.world.resource_mut::<Steps>().0.split_off(0)[..count].to_vec()
+    }
+
+    #[test]
+    fn simulation_does_not_depend_on_frame_rate() {
+        let at_30 = simulate(1.0 / 30.0, 60);
+        let at_144 = simulate(1.0 / 144.0, 60);
+
+        for (slow, fast) in at_30.iter().zip(at_144.iter()) {
+            assert_eq!(slow.current, fast.current);
+            assert_eq!(slow.previous, fast.previous);
+            assert_eq!(slow.velocity, fast.velocity);
+        }
+        // it walked off the ledge and landed on the lower floor
+        let last = at_30.last().unwrap().current;
+        assert!(last.x > 0.0 && last.x < 48.0);
+        assert_eq!(last.y, -12.0);
+    }
+
+    #[test]
+    fn transforms_are_interpolated_between_steps() {
+        let position = Position {
+            previous: Vec2::new(10.0, 20.0),
+            current: Vec2::new(14.0, 12.0),
+            velocity: Vec2::ZERO,
+        };
+        assert_eq!(position.interpolated(0.0), Vec2::new(10.0, 20.0));
+        assert_eq!(position.interpolated(0.25), Vec2::new(11.0, 18.0));
+        assert_eq!(position.interpolated(1.0), Vec2::new(14.0, 12.0));
+        // the accumulator never holds a whole step, but clamp anyway
+        assert_eq!(position.interpolated(1.5), Vec2::new(14.0, 12.0));
+
+        let halfway = position.interpolated(0.6);
+        assert_eq!(PixelSnap::Off.apply(halfway), Vec2::new(12.4, 15.2));
+        assert_eq!(PixelSnap::Grid(1.0).apply(halfway), Vec2::new(12.0, 15.0));
+        assert_eq!(PixelSnap::Grid(2.0).apply(halfway), Vec2::new(12.0, 16.0));
+    }
+}
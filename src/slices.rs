@@ -0,0 +1,208 @@
+use bevy::prelude::*;
+use bevy::sprite::Anchor;
+use bevy::utils::HashMap;
+
+use crate::aseprite::{Aseprite, SliceKey};
+use crate::atlas::SpriteSheetMeta;
+
+pub struct SlicesPlugin;
+
+impl Plugin for SlicesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(CoreStage::PostUpdate, apply_frame_anchors);
+    }
+}
+
+/* Named per-frame points from Aseprite slices, e.g. the `hand` point weapons are
+attached to. Results are in the entity's local space: y up, origin
+at the centre of the untrimmed frame, mirrored when the sprite is flipped.
+ */
+#[derive(Component, Clone, Default)]
+pub struct FrameSlices {
+    // size of the untrimmed frame the slices were drawn on
+    pub frame_size: Vec2,
+    // keys sorted by frame
+    pub slices: HashMap<String, Vec<SliceKey>>,
+}
+
+impl FrameSlices {
+    pub fn from_aseprite(aseprite: &Aseprite) -> Self {
+        let mut frame_slices = FrameSlices {
+            frame_size: aseprite.size.as_vec2(),
+            ..Default::default()
+        };
+        for slice in aseprite.slices.iter() {
+            frame_slices.insert(&slice.name, slice.keys.clone());
+        }
+        frame_slices
+    }
+
+    pub fn from_sheet(meta: &SpriteSheetMeta) -> Self {
+        let mut frame_slices = FrameSlices {
+            frame_size: meta.frames.first().map_or(Vec2::ZERO, |frame| {
+                Vec2::new(frame.source_size.w as f32, frame.source_size.h as f32)
+            }),
+            ..Default::default()
+        };
+        for slice in meta.meta.slices.iter() {
+            let keys = slice
+                .keys
+                .iter()
+                .map(|key| SliceKey {
+                    frame: key.frame,
+                    position: IVec2::new(key.bounds.x as i32, key.bounds.y as i32),
+                    size: UVec2::new(key.bounds.w, key.bounds.h),
+                    center: key.center.map(|center| {
+                        (
+                            IVec2::new(center.x as i32, center.y as i32),
+                            UVec2::new(center.w, center.h),
+                        )
+                    }),
+                    pivot: key.pivot.map(|pivot| IVec2::new(pivot.x, pivot.y)),
+                })
+                .collect();
+            frame_slices.insert(&slice.name, keys);
+        }
+        frame_slices
+    }
+
+    fn insert(&mut self, name: &str, mut keys: Vec<SliceKey>) {
+        keys.sort_by_key(|key| key.frame);
+        self.slices.insert(name.to_string(), keys);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slices.is_empty()
+    }
+
+    /// The key in effect on `frame`: the last one at or before it. An empty key means
+    /// the slice was removed from that frame on.
+    pub fn key(&self, name: &str, frame: usize) -> Option<&SliceKey> {
+        self.slices
+            .get(name)?
+            .iter()
+            .take_while(|key| key.frame <= frame)
+            .last()
+            .filter(|key| key.size != UVec2::ZERO)
+    }
+
+    /// The slice's pivot, or the centre of its rect when it has none.
+    pub fn point(&self, name: &str, sprite: &TextureAtlasSprite) -> Option<Vec2> {
+        let key = self.key(name, sprite.index)?;
+        let point = match key.pivot {
+            Some(pivot) => (key.position + pivot).as_vec2(),
+            None => key.position.as_vec2() + key.size.as_vec2() / 2.0,
+        };
+        Some(self.to_local(point, sprite.flip_x))
+    }
+
+    fn to_local(&self, point: Vec2, flip_x: bool) -> Vec2 {
+        let local = Vec2::new(
+            point.x - self.frame_size.x / 2.0,
+            self.frame_size.y / 2.0 - point.y,
+        );
+        if flip_x {
+            Vec2::new(-local.x, local.y)
+        } else {
+            local
+        }
+    }
+}
+
+/// Anchor of each atlas frame that keeps trimmed frames where the untrimmed frame would be.
+#[derive(Component, Deref)]
+pub struct FrameAnchors(Vec<Vec2>);
+
+impl FrameAnchors {
+    /// `None` when no frame of the sheet is trimmed.
+    pub fn from_sheet(meta: &SpriteSheetMeta) -> Option<Self> {
+        if !meta.frames.iter().any(|frame| frame.trimmed) {
+            return None;
+        }
+        Some(FrameAnchors(
+            meta.frames
+                .iter()
+                .map(|frame| {
+                    let trimmed = &frame.sprite_source_size;
+                    let size = Vec2::new(trimmed.w.max(1) as f32, trimmed.h.max(1) as f32);
+                    let source = Vec2::new(frame.source_size.w as f32, frame.source_size.h as f32);
+                    // from the centre of the trimmed frame to the centre of the source frame
+                    let offset =
+                        source / 2.0 - (Vec2::new(trimmed.x as f32, trimmed.y as f32) + size / 2.0);
+                    Vec2::new(offset.x / size.x, -offset.y / size.y)
+                })
+                .collect(),
+        ))
+    }
+}
+
+pub fn apply_frame_anchors(mut query: Query<(&FrameAnchors, &mut TextureAtlasSprite)>) {
+    for (anchors, mut sprite) in query.iter_mut() {
+        let mut anchor = match anchors.get(sprite.index) {
+            Some(anchor) => *anchor,
+            None => continue,
+        };
+        // the offset is in texture space, which flips with the sprite
+        if sprite.flip_x {
+            anchor.x = -anchor.x;
+        }
+        if sprite.flip_y {
+            anchor.y = -anchor.y;
+        }
+        if sprite.anchor.as_vec() != anchor {
+            sprite.anchor = Anchor::Custom(anchor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hand(frame: usize, position: IVec2, pivot: Option<IVec2>) -> SliceKey {
+        SliceKey {
+            frame,
+            position,
+            size: UVec2::new(4, 2),
+            center: None,
+            pivot,
+        }
+    }
+
+    #[test]
+    fn points_follow_keys_and_flips() {
+        let mut slices = FrameSlices {
+            frame_size: Vec2::new(20.0, 10.0),
+            ..Default::default()
+        };
+        slices.insert(
+            "hand",
+            vec![
+                hand(3, IVec2::new(2, 2), Some(IVec2::new(1, 1))),
+                hand(0, IVec2::new(14, 4), None),
+            ],
+        );
+        let mut sprite = TextureAtlasSprite::new(1);
+        // centre of the rect, from the frame's centre with y up
+        assert_eq!(slices.point("hand", &sprite), Some(Vec2::new(6.0, 0.0)));
+        sprite.flip_x = true;
+        assert_eq!(slices.point("hand", &sprite), Some(Vec2::new(-6.0, 0.0)));
+        sprite.flip_x = false;
+        sprite.index = 5;
+        assert_eq!(slices.point("hand", &sprite), Some(Vec2::new(-7.0, 2.0)));
+        assert_eq!(slices.point("hurtbox", &sprite), None);
+    }
+
+    #[test]
+    fn empty_keys_remove_the_slice() {
+        let mut slices = FrameSlices {
+            frame_size: Vec2::new(20.0, 10.0),
+            ..Default::default()
+        };
+        let mut removed = hand(2, IVec2::ZERO, None);
+        removed.size = UVec2::ZERO;
+        slices.insert("hand", vec![hand(0, IVec2::new(14, 4), None), removed]);
+        assert!(slices.key("hand", 1).is_some());
+        assert!(slices.key("hand", 2).is_none());
+    }
+}
@@ -0,0 +1,110 @@
+use bevy::audio::AudioSink;
+use bevy::prelude::*;
+
+use crate::ldtk_component;
+use crate::level_entities::{LevelFields, RegisterLdtkEntityExt};
+use crate::level_streaming::LevelWorld;
+
+pub struct SoundPlugin;
+
+impl Plugin for SoundPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<PlaySound>()
+            .add_event::<ChangeMusic>()
+            .init_resource::<Music>()
+            .add_ldtk_level_component::<LevelMusic>()
+            .add_system(play_sounds)
+            .add_system(play_level_music.before(change_music))
+            .add_system(change_music);
+    }
+}
+
+/// Plays a sound effect once; the path is relative to the assets folder.
+pub struct PlaySound {
+    pub path: String,
+}
+
+/// Replaces the background music; None stops it.
+pub struct ChangeMusic {
+    pub track: Option<String>,
+}
+
+/* Levels with a `Music` field play that track once the player is in them; levels
+without one keep whatever was playing.
+ */
+ldtk_component! {
+    #[derive(Component)]
+    pub struct LevelMusic {
+        pub music: Option<String>,
+    }
+}
+
+#[derive(Default)]
+struct Music {
+    track: Option<String>,
+    sink: Option<Handle<AudioSink>>,
+}
+
+fn play_sounds(
+    asset_server: Res<AssetServer>,
+    audio: Res<Audio>,
+    mut events: EventReader<PlaySound>,
+) {
+    for event in events.iter() {
+        audio.play(asset_server.load(event.path.as_str()));
+    }
+}
+
+fn change_music(
+    asset_server: Res<AssetServer>,
+    audio: Res<Audio>,
+    sinks: Res<Assets<AudioSink>>,
+    mut music: ResMut<Music>,
+    mut events: EventReader<ChangeMusic>,
+) {
+    // only the last change of the frame counts
+    let track = match events.iter().last() {
+        Some(event) => event.track.clone(),
+        None => return,
+    };
+    if track == music.track {
+        return;
+    }
+    if let Some(sink) = music.sink.take().and_then(|sink| sinks.get(&sink)) {
+        sink.pause();
+    }
+    music.sink = track
+        .as_ref()
+        .map(|track| sinks.get_handle(audio.play(asset_server.load(track.as_str()))));
+    music.track = track;
+}
+
+fn play_level_music(
+    world: Option<Res<LevelWorld>>,
+    mut events: EventWriter<ChangeMusic>,
+    levels: Query<(&LevelFields, &LevelMusic)>,
+    // the level whose music was last looked at
+    mut current: Local<Option<String>>,
+) {
+    let world = match world {
+        Some(world) => world,
+        None => return,
+    };
+    if current.as_deref() == Some(world.current.as_str()) {
+        return;
+    }
+    // the level's root may not have its fields yet
+    let music = match levels
+        .iter()
+        .find(|(fields, _)| fields.level == world.current)
+    {
+        Some((_, music)) => music,
+        None => return,
+    };
+    *current = Some(world.current.clone());
+    if let Some(track) = &music.music {
+        events.send(ChangeMusic {
+            track: Some(track.clone()),
+        });
+    }
+}
@@ -0,0 +1,573 @@
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
+use bevy::sprite::{Anchor, MaterialMesh2dBundle, Mesh2dHandle};
+use bevy::utils::{HashMap, HashSet};
+use serde::Deserialize;
+
+use crate::autotile::AutoLayer;
+use crate::custom_parallax::LayerParallax;
+use crate::ldtk::{parse_color, LayerInstance, LdtkProject, Level, TilesetDef};
+use crate::render_layers::RenderLayer;
+
+/// Width and height of a chunk, in tiles. Each chunk of a layer is one mesh.
+pub const CHUNK_SIZE: i32 = 32;
+
+pub struct TilemapPlugin;
+
+impl Plugin for TilemapPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(CoreStage::PostUpdate, rebuild_dirty_chunks)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                animate_tiles.before(rebuild_dirty_chunks),
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    // id in the layer's tileset
+    pub id: i32,
+    pub flip_x: bool,
+    pub flip_y: bool,
+}
+
+/* Tile animations are authored in LDtk as the custom data of the tileset's first frame,
+e.g. `frames: [40, 41, 42, 43], frame_time: 0.15`. Tiles placed with that id cycle
+through the frames; all of them share one clock, so they stay in step.
+ */
+#[derive(Deserialize, Clone)]
+pub struct TileAnimation {
+    pub frames: Vec<i32>,
+    // seconds per frame
+    pub frame_time: f32,
+}
+
+impl TileAnimation {
+    pub fn frame_at(&self, seconds: f64) -> i32 {
+        let step = (seconds / self.frame_time.max(0.001) as f64) as usize;
+        self.frames[step % self.frames.len()]
+    }
+}
+
+/// Animations by tile id, from the custom data of the tileset's tiles.
+pub fn tile_animations(tileset: &TilesetDef) -> HashMap<i32, TileAnimation> {
+    let mut animations = HashMap::default();
+    for custom in tileset.custom_data.iter() {
+        // the data is the fields of a struct, without the parentheses
+        match ron::de::from_str::<TileAnimation>(&format!("({})", custom.data)) {
+            Ok(animation) if !animation.frames.is_empty() => {
+                animations.insert(custom.tile_id, animation);
+            }
+            Ok(_) => {}
+            Err(err) => warn!(
+                "{}: custom data of tile {} is not an animation: {}",
+                tileset.identifier, custom.tile_id, err
+            ),
+        }
+    }
+    animations
+}
+
+// where tile ids are in the tileset image
+#[derive(Clone, Copy)]
+struct TileSource {
+    image_size: Vec2,
+    tile_size: i32,
+    columns: i32,
+    spacing: i32,
+    padding: i32,
+}
+
+impl TileSource {
+    fn from_def(tileset: &TilesetDef) -> Self {
+        TileSource {
+            image_size: Vec2::new(tileset.px_wid as f32, tileset.px_hei as f32),
+            tile_size: tileset.tile_grid_size,
+            columns: tileset.columns(),
+            spacing: tileset.spacing,
+            padding: tileset.padding,
+        }
+    }
+
+    fn uv_rect(&self, id: i32) -> (Vec2, Vec2) {
+        let step = self.tile_size + self.spacing;
+        let min = Vec2::new(
+            (self.padding + (id % self.columns) * step) as f32,
+            (self.padding + (id / self.columns) * step) as f32,
+        );
+        let max = min + Vec2::splat(self.tile_size as f32);
+        (min / self.image_size, max / self.image_size)
+    }
+}
+
+/* One LDtk tile or auto-layer. Tiles are kept per cell (a cell can stack several
+auto-layer tiles, bottom first) and drawn by chunk entities; changing a tile only
+rebuilds the mesh of its chunk.
+ */
+#[derive(Component)]
+pub struct TileLayer {
+    pub identifier: String,
+    pub width: i32,
+    pub height: i32,
+    pub grid_size: f32,
+    pub visible: bool,
+    source: TileSource,
+    material: Handle<ColorMaterial>,
+    cells: Vec<Vec<Tile>>,
+    chunks: HashMap<IVec2, Entity>,
+    dirty: HashSet<IVec2>,
+    animations: HashMap<i32, TileAnimation>,
+    // frame each animated tile id is showing
+    shown_frames: HashMap<i32, i32>,
+    // first vertex of each animated tile's quad, per chunk
+    animated_quads: HashMap<IVec2, Vec<(u32, Tile)>>,
+}
+
+impl TileLayer {
+    fn new(layer: &LayerInstance, tileset: &TilesetDef, material: Handle<ColorMaterial>) -> Self {
+        let mut tile_layer = TileLayer {
+            identifier: layer.identifier.clone(),
+            width: layer.c_wid,
+            height: layer.c_hei,
+            grid_size: layer.grid_size as f32,
+            visible: layer.visible,
+            source: TileSource::from_def(tileset),
+            material,
+            cells: vec![Vec::new(); (layer.c_wid * layer.c_hei).max(0) as usize],
+            chunks: HashMap::default(),
+            dirty: HashSet::default(),
+            animations: tile_animations(tileset),
+            shown_frames: HashMap::default(),
+            animated_quads: HashMap::default(),
+        };
+        for tile in layer.tiles() {
+            let (x, y) = (tile.px[0] / layer.grid_size, tile.px[1] / layer.grid_size);
+            if let Some(index) = tile_layer.index(x, y) {
+                tile_layer.cells[index].push(Tile {
+                    id: tile.t,
+                    flip_x: tile.f & 1 != 0,
+                    flip_y: tile.f & 2 != 0,
+                });
+            }
+        }
+        for chunk_y in 0..(tile_layer.height + CHUNK_SIZE - 1) / CHUNK_SIZE {
+            for chunk_x in 0..(tile_layer.width + CHUNK_SIZE - 1) / CHUNK_SIZE {
+                tile_layer.dirty.insert(IVec2::new(chunk_x, chunk_y));
+            }
+        }
+        tile_layer
+    }
+
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
+        }
+        Some((y * self.width + x) as usize)
+    }
+
+    /// Tiles of a cell, bottom first. Cell (0, 0) is the top-left one.
+    pub fn tiles(&self, x: i32, y: i32) -> &[Tile] {
+        self.index(x, y)
+            .map_or(&[], |index| self.cells[index].as_slice())
+    }
+
+    pub fn set_tiles(&mut self, x: i32, y: i32, tiles: Vec<Tile>) {
+        if let Some(index) = self.index(x, y) {
+            if self.cells[index] != tiles {
+                self.cells[index] = tiles;
+                self.dirty.insert(IVec2::new(
+                    x.div_euclid(CHUNK_SIZE),
+                    y.div_euclid(CHUNK_SIZE),
+                ));
+            }
+        }
+    }
+
+    pub fn set_tile(&mut self, x: i32, y: i32, tile: Option<Tile>) {
+        self.set_tiles(x, y, tile.into_iter().collect());
+    }
+
+    // the tile id drawn for `tile` right now
+    fn shown_id(&self, tile: &Tile) -> i32 {
+        self.shown_frames.get(&tile.id).copied().unwrap_or(tile.id)
+    }
+
+    fn tile_uvs(&self, tile: &Tile) -> [[f32; 2]; 4] {
+        let (mut uv_min, mut uv_max) = self.source.uv_rect(self.shown_id(tile));
+        if tile.flip_x {
+            std::mem::swap(&mut uv_min.x, &mut uv_max.x);
+        }
+        if tile.flip_y {
+            std::mem::swap(&mut uv_min.y, &mut uv_max.y);
+        }
+        [
+            [uv_min.x, uv_max.y],
+            [uv_max.x, uv_max.y],
+            [uv_max.x, uv_min.y],
+            [uv_min.x, uv_min.y],
+        ]
+    }
+
+    fn chunk_mesh(&mut self, chunk: IVec2) -> Option<Mesh> {
+        let mut positions: Vec<[f32; 3]> = Vec::new();
+        let mut uvs: Vec<[f32; 2]> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+        let mut animated: Vec<(u32, Tile)> = Vec::new();
+        let size = self.grid_size;
+
+        for y in chunk.y * CHUNK_SIZE..((chunk.y + 1) * CHUNK_SIZE).min(self.height) {
+            for x in chunk.x * CHUNK_SIZE..((chunk.x + 1) * CHUNK_SIZE).min(self.width) {
+                for tile in self.tiles(x, y) {
+                    // y grows down in the level and up in the world
+                    let (left, top) = (x as f32 * size, -(y as f32) * size);
+                    let first = positions.len() as u32;
+                    if self.animations.contains_key(&tile.id) {
+                        animated.push((first, *tile));
+                    }
+                    positions.extend([
+                        [left, top - size, 0.0],
+                        [left + size, top - size, 0.0],
+                        [left + size, top, 0.0],
+                        [left, top, 0.0],
+                    ]);
+                    uvs.extend(self.tile_uvs(tile));
+                    indices.extend([first, first + 1, first + 2, first, first + 2, first + 3]);
+                }
+            }
+        }
+        if animated.is_empty() {
+            self.animated_quads.remove(&chunk);
+        } else {
+            self.animated_quads.insert(chunk, animated);
+        }
+        if positions.is_empty() {
+            return None;
+        }
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_NORMAL,
+            vec![[0.0, 0.0, 1.0]; positions.len()],
+        );
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.set_indices(Some(Indices::U32(indices)));
+        Some(mesh)
+    }
+}
+
+#[derive(Component)]
+pub struct TileChunk {
+    pub coord: IVec2,
+}
+
+/// Draws the tile layers of `level` under `root`, the level's entity at `origin`, its
+/// top-left corner in the world.
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_tile_layers(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    materials: &mut Assets<ColorMaterial>,
+    project_handle: &Handle<LdtkProject>,
+    project: &LdtkProject,
+    level: &Level,
+    root: Entity,
+    origin: Vec2,
+) {
+    let level_size = Vec2::new(level.px_wid as f32, level.px_hei as f32);
+    // where the level is centred in the view when LDtk shows layers without parallax
+    let center = origin + Vec2::new(level_size.x, -level_size.y) / 2.0;
+
+    // the project colour fills the space around levels, as in the editor, and the level
+    // colour the level itself, in front of the parallax backgrounds
+    commands.insert_resource(ClearColor(parse_color(&project.bg_color)));
+    let background = commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: parse_color(&level.bg_color),
+                custom_size: Some(level_size),
+                anchor: Anchor::TopLeft,
+                ..Default::default()
+            },
+            transform: Transform::from_xyz(0.0, 0.0, RenderLayer::LevelBack.z(0.0)),
+            ..Default::default()
+        })
+        .id();
+    commands.entity(root).add_child(background);
+
+    // LDtk lists the top-most layer first; tile layers above the entity layer are drawn
+    // in front of the entities
+    let layer_count = level.layers().len();
+    let entity_layer = level
+        .layers()
+        .iter()
+        .position(|layer| layer.layer_type == "Entities");
+    for (index, layer) in level.layers().iter().enumerate() {
+        let tileset = match layer.tileset_def_uid.and_then(|uid| project.tileset(uid)) {
+            Some(tileset) => tileset,
+            None => continue,
+        };
+        let path = match &tileset.path {
+            Some(path) => path,
+            None => continue,
+        };
+        let def = project.layer_def(layer.layer_def_uid);
+        // auto-layers are filled in from their rules even if exported without tiles
+        let has_rules = def.map_or(false, |def| !def.auto_rule_groups.is_empty());
+        if layer.tiles().is_empty() && !has_rules {
+            continue;
+        }
+
+        let material = materials.add(ColorMaterial {
+            color: Color::rgba(1.0, 1.0, 1.0, layer.opacity),
+            texture: Some(asset_server.load(path.as_str())),
+        });
+        let render_layer = match entity_layer {
+            Some(entity_layer) if index < entity_layer => RenderLayer::LevelFront,
+            _ => RenderLayer::LevelBack,
+        };
+        // above the background
+        let z = render_layer.z((layer_count - index) as f32);
+        let offset = Vec2::new(
+            layer.px_total_offset_x as f32,
+            -layer.px_total_offset_y as f32,
+        );
+        let mut layer_entity = commands.spawn_bundle(TransformBundle::from_transform(
+            Transform::from_translation(offset.extend(z)),
+        ));
+        layer_entity.insert(TileLayer::new(layer, tileset, material));
+        if let Some(def) =
+            def.filter(|def| def.parallax_factor_x != 0.0 || def.parallax_factor_y != 0.0)
+        {
+            layer_entity.insert(LayerParallax {
+                factor: Vec2::new(def.parallax_factor_x, def.parallax_factor_y),
+                scaling: def.parallax_scaling,
+                center,
+                pivot: level_size * Vec2::new(0.5, -0.5),
+                base: offset.extend(z),
+            });
+        }
+        if has_rules {
+            layer_entity.insert(AutoLayer {
+                project: project_handle.clone(),
+                layer_def_uid: layer.layer_def_uid,
+                tileset_def_uid: tileset.uid,
+                seed: layer.seed,
+                optional_rules: layer.optional_rules.clone(),
+                origin,
+                evaluated: false,
+            });
+        }
+        let layer_entity = layer_entity.id();
+        commands.entity(root).add_child(layer_entity);
+    }
+}
+
+pub fn rebuild_dirty_chunks(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut layers: Query<(Entity, &mut TileLayer)>,
+    chunks: Query<&Mesh2dHandle, With<TileChunk>>,
+) {
+    for (layer_entity, mut layer) in layers.iter_mut() {
+        if layer.dirty.is_empty() {
+            continue;
+        }
+        let dirty: Vec<IVec2> = layer.dirty.drain().collect();
+        for coord in dirty {
+            let mesh = layer.chunk_mesh(coord);
+            let existing = layer.chunks.get(&coord).copied();
+            match (mesh, existing) {
+                (Some(mesh), Some(chunk)) => {
+                    if let Some(chunk_mesh) = chunks
+                        .get(chunk)
+                        .ok()
+                        .and_then(|handle| meshes.get_mut(&handle.0))
+                    {
+                        *chunk_mesh = mesh;
+                    }
+                }
+                (Some(mesh), None) => {
+                    let chunk = commands
+                        .spawn_bundle(MaterialMesh2dBundle {
+                            mesh: meshes.add(mesh).into(),
+                            material: layer.material.clone(),
+                            visibility: Visibility {
+                                is_visible: layer.visible,
+                            },
+                            ..Default::default()
+                        })
+                        .insert(TileChunk { coord })
+                        .id();
+                    commands.entity(layer_entity).add_child(chunk);
+                    layer.chunks.insert(coord, chunk);
+                }
+                (None, Some(chunk)) => {
+                    commands.entity(chunk).despawn();
+                    layer.chunks.remove(&coord);
+                }
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+/// Steps animated tiles by rewriting the UVs of their quads in place; chunk meshes are
+/// only rebuilt when their tiles change.
+fn animate_tiles(
+    time: Res<Time>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut layers: Query<&mut TileLayer>,
+    chunks: Query<&Mesh2dHandle, With<TileChunk>>,
+) {
+    let seconds = time.seconds_since_startup();
+    for mut layer in layers.iter_mut() {
+        if layer.animations.is_empty() {
+            continue;
+        }
+        let mut changed = false;
+        let frames: Vec<(i32, i32)> = layer
+            .animations
+            .iter()
+            .map(|(id, animation)| (*id, animation.frame_at(seconds)))
+            .collect();
+        for (id, frame) in frames {
+            if layer.shown_frames.insert(id, frame) != Some(frame) {
+                changed = true;
+            }
+        }
+        if !changed {
+            continue;
+        }
+
+        let layer = &*layer;
+        for (coord, quads) in layer.animated_quads.iter() {
+            // dirty chunks get the new frames when they are rebuilt
+            if layer.dirty.contains(coord) {
+                continue;
+            }
+            let mesh = layer
+                .chunks
+                .get(coord)
+                .and_then(|chunk| chunks.get(*chunk).ok())
+                .and_then(|handle| meshes.get_mut(&handle.0));
+            if let Some(VertexAttributeValues::Float32x2(uvs)) =
+                mesh.and_then(|mesh| mesh.attribute_mut(Mesh::ATTRIBUTE_UV_0))
+            {
+                for (first, tile) in quads {
+                    let first = *first as usize;
+                    if let Some(quad) = uvs.get_mut(first..first + 4) {
+                        quad.copy_from_slice(&layer.tile_uvs(tile));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::AssetPlugin;
+    use bevy::core::CorePlugin;
+
+    use super::*;
+
+    fn tileset(custom_data: &str) -> TilesetDef {
+        serde_json::from_str(&format!(
+            r#"{{"uid": 1, "identifier": "Test", "relPath": null, "pxWid": 64, "pxHei": 64,
+                "tileGridSize": 16, "spacing": 0, "padding": 0, "customData": {}}}"#,
+            custom_data
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn custom_data_becomes_animations() {
+        let animations = tile_animations(&tileset(
+            r#"[
+                {"tileId": 4, "data": "frames: [4, 5, 6], frame_time: 0.25"},
+                {"tileId": 7, "data": "frames: [], frame_time: 0.25"},
+                {"tileId": 8, "data": "a door"},
+                {"tileId": 9, "data": "frames: [9, 10]"}
+            ]"#,
+        ));
+        assert_eq!(animations.len(), 1);
+        assert_eq!(animations[&4].frames, vec![4, 5, 6]);
+        assert_eq!(animations[&4].frame_time, 0.25);
+    }
+
+    #[test]
+    fn frames_follow_the_shared_clock() {
+        let animation = TileAnimation {
+            frames: vec![4, 5, 6],
+            frame_time: 0.25,
+        };
+        assert_eq!(animation.frame_at(0.0), 4);
+        assert_eq!(animation.frame_at(0.24), 4);
+        assert_eq!(animation.frame_at(0.25), 5);
+        assert_eq!(animation.frame_at(0.6), 6);
+        // and wrap around
+        assert_eq!(animation.frame_at(0.75), 4);
+        assert_eq!(animation.frame_at(1.0), 5);
+    }
+
+    #[test]
+    fn animated_tiles_rewrite_their_quads() {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin)
+            .add_plugin(AssetPlugin)
+            .add_asset::<Mesh>()
+            .add_plugin(TilemapPlugin);
+
+        // an animated tile next to a still one
+        let tileset = tileset(r#"[{"tileId": 4, "data": "frames: [4, 5], frame_time: 1000.0"}]"#);
+        let layer: LayerInstance = serde_json::from_str(
+            r#"{"__identifier": "Tiles", "__type": "Tiles", "__cWid": 2, "__cHei": 1,
+                "__gridSize": 16, "__opacity": 1.0, "__pxTotalOffsetX": 0,
+                "__pxTotalOffsetY": 0, "__tilesetDefUid": 1, "layerDefUid": 2, "visible": true,
+                "gridTiles": [{"px": [0, 0], "src": [0, 16], "f": 0, "t": 4},
+                              {"px": [16, 0], "src": [16, 0], "f": 0, "t": 1}]}"#,
+        )
+        .unwrap();
+        let layer = app
+            .world
+            .spawn()
+            .insert(TileLayer::new(&layer, &tileset, Handle::default()))
+            .id();
+        app.update();
+
+        let uvs = |app: &App| {
+            let chunk = app.world.get::<TileLayer>(layer).unwrap().chunks[&IVec2::ZERO];
+            let handle = app.world.get::<Mesh2dHandle>(chunk).unwrap();
+            let mesh = app.world.resource::<Assets<Mesh>>().get(&handle.0).unwrap();
+            match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+                Some(VertexAttributeValues::Float32x2(uvs)) => uvs.clone(),
+                _ => panic!("chunk mesh has no uvs"),
+            }
+        };
+        let source = TileSource::from_def(&tileset);
+        let quad = |id| {
+            let (min, max) = source.uv_rect(id);
+            vec![
+                [min.x, max.y],
+                [max.x, max.y],
+                [max.x, min.y],
+                [min.x, min.y],
+            ]
+        };
+        assert_eq!(uvs(&app), [quad(4), quad(1)].concat());
+
+        // a new frame is written into the chunk's mesh without rebuilding it
+        app.world
+            .get_mut::<TileLayer>(layer)
+            .unwrap()
+            .animations
+            .get_mut(&4)
+            .unwrap()
+            .frames = vec![6, 7];
+        app.update();
+        assert!(app.world.get::<TileLayer>(layer).unwrap().dirty.is_empty());
+        assert_eq!(uvs(&app), [quad(6), quad(1)].concat());
+    }
+}
@@ -0,0 +1,344 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+use crate::collision::Collider;
+use crate::ldtk::LdtkProject;
+use crate::ldtk_fields::{field, EntityRef};
+use crate::level_entities::{EntityBounds, LevelEntity, RegisterLdtkEntityExt};
+use crate::level_streaming::{LevelStates, LevelWorld};
+use crate::prefab::SpawnPrefabExt;
+use crate::simulation::Position;
+use crate::sound::{ChangeMusic, PlaySound};
+use crate::{ldtk_component, ldtk_enum, Enemy, Player, Projectile};
+
+pub struct TriggersPlugin;
+
+impl Plugin for TriggersPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<TriggerEvent>()
+            .add_event::<StartDialogue>()
+            .add_ldtk_component::<Trigger>("Trigger")
+            .register_ldtk_entity("Trigger", |entity, instance| {
+                let points = match field::<Option<Vec<IVec2>>>(&instance.fields, "spawn_at") {
+                    Ok(points) => points.unwrap_or_default(),
+                    Err(err) => {
+                        warn!("{}: Trigger {}: {}", instance.level, instance.iid, err);
+                        Vec::new()
+                    }
+                };
+                let mut spawn_points: Vec<Vec2> = points
+                    .into_iter()
+                    .map(|cell| instance.cell_center(cell))
+                    .collect();
+                if spawn_points.is_empty() {
+                    spawn_points.push(instance.center());
+                }
+                entity.insert(TriggerVolume {
+                    spawn_points,
+                    inside: HashSet::default(),
+                    cooldown: 0.0,
+                });
+            })
+            .add_system(update_triggers)
+            .add_system(run_trigger_actions.after(update_triggers));
+    }
+}
+
+/* Trigger volumes, placed in LDtk as "Trigger" entities. Entities passing the
+`Filter` (the player, when it's left empty) send a `TriggerEvent` when they enter the
+volume, every frame they stay in it and when they leave; the trigger's actions run on
+the phase set in `On`, entering by default. The actions are fields too, run in this
+order when set:
+- `Open`: locked doors to open
+- `Spawn_prefab`: prefabs spawned at the `Spawn_at` points in turn, or the trigger's centre
+- `Sound`: sound effect to play
+- `Music`: background music to switch to
+- `Dialogue`: dialogue to start, sent as `StartDialogue`
+`Once` triggers act a single time, which their level's state remembers; the others
+can be held back for `Cooldown` seconds after acting.
+ */
+ldtk_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TriggerFilter {
+        Player,
+        Enemies,
+        Projectiles,
+    }
+}
+
+ldtk_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TriggerPhase {
+        Enter,
+        Stay,
+        Exit,
+    }
+}
+
+ldtk_component! {
+    #[derive(Component)]
+    pub struct Trigger {
+        pub filter: Option<Vec<TriggerFilter>>,
+        pub on: Option<TriggerPhase>,
+        pub once: Option<bool>,
+        pub cooldown: Option<f32>,
+        pub open: Option<Vec<EntityRef>>,
+        pub spawn_prefab: Option<Vec<String>>,
+        pub sound: Option<String>,
+        pub music: Option<String>,
+        pub dialogue: Option<String>,
+    }
+}
+
+impl Trigger {
+    fn accepts(&self, player: bool, enemy: bool, projectile: bool) -> bool {
+        let filters = match self.filter.as_deref() {
+            Some(filters) if !filters.is_empty() => filters,
+            _ => &[TriggerFilter::Player],
+        };
+        filters.iter().any(|filter| match filter {
+            TriggerFilter::Player => player,
+            TriggerFilter::Enemies => enemy,
+            TriggerFilter::Projectiles => projectile,
+        })
+    }
+}
+
+#[derive(Component)]
+pub struct TriggerVolume {
+    // world positions prefabs are spawned at
+    pub spawn_points: Vec<Vec2>,
+    // entities in the volume as of the last update
+    inside: HashSet<Entity>,
+    // seconds until the actions can run again
+    cooldown: f32,
+}
+
+pub struct TriggerEvent {
+    pub trigger: Entity,
+    // LDtk iid of the trigger
+    pub iid: String,
+    // what entered, stayed in or left the volume
+    pub entity: Entity,
+    pub phase: TriggerPhase,
+}
+
+/// Sent by triggers with a `Dialogue`, for the dialogue UI.
+pub struct StartDialogue {
+    pub dialogue: String,
+    pub trigger: Entity,
+    pub entity: Entity,
+}
+
+// whether a `Once` trigger has acted
+fn spent(states: &LevelStates, trigger: &LevelEntity) -> bool {
+    states
+        .get(&trigger.level)
+        .map_or(false, |state| state.flags.contains(&trigger.iid))
+}
+
+#[allow(clippy::type_complexity)]
+fn update_triggers(
+    time: Res<Time>,
+    states: Res<LevelStates>,
+    mut events: EventWriter<TriggerEvent>,
+    mut triggers: Query<(
+        Entity,
+        &Trigger,
+        &mut TriggerVolume,
+        &EntityBounds,
+        &LevelEntity,
+    )>,
+    candidates: Query<
+        (
+            Entity,
+            &Position,
+            Option<&Collider>,
+            Option<&Player>,
+            Option<&Enemy>,
+            Option<&Projectile>,
+        ),
+        Or<(With<Player>, With<Enemy>, With<Projectile>)>,
+    >,
+) {
+    for (trigger_entity, trigger, mut volume, bounds, level_entity) in triggers.iter_mut() {
+        volume.cooldown = (volume.cooldown - time.delta_seconds()).max(0.0);
+        if trigger.once.unwrap_or(false) && spent(&states, level_entity) {
+            continue;
+        }
+
+        let inside: HashSet<Entity> = candidates
+            .iter()
+            .filter(|(_, _, _, player, enemy, projectile)| {
+                trigger.accepts(player.is_some(), enemy.is_some(), projectile.is_some())
+            })
+            .filter(|(_, position, collider, ..)| {
+                let half_size = collider.map_or(Vec2::ZERO, |collider| collider.half_size);
+                bounds.overlaps(position.current, half_size)
+            })
+            .map(|(entity, ..)| entity)
+            .collect();
+        let mut send = |entity: Entity, phase: TriggerPhase| {
+            events.send(TriggerEvent {
+                trigger: trigger_entity,
+                iid: level_entity.iid.clone(),
+                entity,
+                phase,
+            })
+        };
+        // despawned entities leave too
+        for entity in volume.inside.difference(&inside) {
+            send(*entity, TriggerPhase::Exit);
+        }
+        for entity in inside.iter() {
+            if volume.inside.contains(entity) {
+                send(*entity, TriggerPhase::Stay);
+            } else {
+                send(*entity, TriggerPhase::Enter);
+            }
+        }
+        volume.inside = inside;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_trigger_actions(
+    mut commands: Commands,
+    projects: Res<Assets<LdtkProject>>,
+    world: Option<Res<LevelWorld>>,
+    mut states: ResMut<LevelStates>,
+    mut events: EventReader<TriggerEvent>,
+    mut sounds: EventWriter<PlaySound>,
+    mut music: EventWriter<ChangeMusic>,
+    mut dialogues: EventWriter<StartDialogue>,
+    mut triggers: Query<(&Trigger, &mut TriggerVolume, &LevelEntity)>,
+) {
+    let project = world.and_then(|world| projects.get(&world.project));
+    for event in events.iter() {
+        let (trigger, mut volume, level_entity) = match triggers.get_mut(event.trigger) {
+            Ok(trigger) => trigger,
+            Err(_) => continue,
+        };
+        if event.phase != trigger.on.unwrap_or(TriggerPhase::Enter) || volume.cooldown > 0.0 {
+            continue;
+        }
+        if trigger.once.unwrap_or(false) {
+            // several entities may set it off in the same frame
+            if spent(&states, level_entity) {
+                continue;
+            }
+            states
+                .get_mut(&level_entity.level)
+                .flags
+                .insert(level_entity.iid.clone());
+        }
+        volume.cooldown = trigger.cooldown.unwrap_or(0.0);
+
+        for door in trigger.open.iter().flatten() {
+            // the door's level may not be loaded, its state is there anyway
+            match project.and_then(|project| project.level_by_iid(&door.level_iid)) {
+                Some(level) => {
+                    states
+                        .get_mut(&level.identifier)
+                        .flags
+                        .insert(door.entity_iid.clone());
+                }
+                None => warn!(
+                    "{}: trigger {} opens a door in a level that does not exist",
+                    level_entity.level, level_entity.iid
+                ),
+            }
+        }
+        for (index, prefab) in trigger.spawn_prefab.iter().flatten().enumerate() {
+            let point = volume.spawn_points[index % volume.spawn_points.len()];
+            // unloaded with the trigger's level
+            commands.spawn_prefab(prefab, point).insert(LevelEntity {
+                iid: level_entity.iid.clone(),
+                level: level_entity.level.clone(),
+            });
+        }
+        if let Some(path) = &trigger.sound {
+            sounds.send(PlaySound { path: path.clone() });
+        }
+        if let Some(track) = &trigger.music {
+            music.send(ChangeMusic {
+                track: Some(track.clone()),
+            });
+        }
+        if let Some(dialogue) = &trigger.dialogue {
+            dialogues.send(StartDialogue {
+                dialogue: dialogue.clone(),
+                trigger: event.trigger,
+                entity: event.entity,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::AssetPlugin;
+    use bevy::core::CorePlugin;
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::prefab::PendingPrefab;
+
+    #[test]
+    fn spawned_prefabs_belong_to_the_trigger_level() {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin)
+            .add_plugin(AssetPlugin)
+            .add_asset::<LdtkProject>()
+            .init_resource::<LevelStates>()
+            .add_event::<TriggerEvent>()
+            .add_event::<StartDialogue>()
+            .add_event::<PlaySound>()
+            .add_event::<ChangeMusic>()
+            .add_system(run_trigger_actions);
+
+        let trigger = app
+            .world
+            .spawn()
+            .insert(Trigger {
+                filter: None,
+                on: None,
+                once: None,
+                cooldown: None,
+                open: None,
+                spawn_prefab: Some(vec!["slime".to_string(), "bat".to_string()]),
+                sound: None,
+                music: None,
+                dialogue: None,
+            })
+            .insert(TriggerVolume {
+                spawn_points: vec![Vec2::new(8.0, -8.0)],
+                inside: HashSet::default(),
+                cooldown: 0.0,
+            })
+            .insert(LevelEntity {
+                iid: "trigger".to_string(),
+                level: "Cave".to_string(),
+            })
+            .id();
+        let player = app.world.spawn().id();
+        app.world
+            .resource_mut::<Events<TriggerEvent>>()
+            .send(TriggerEvent {
+                trigger,
+                iid: "trigger".to_string(),
+                entity: player,
+                phase: TriggerPhase::Enter,
+            });
+        app.update();
+
+        let mut spawned = app
+            .world
+            .query_filtered::<&LevelEntity, With<PendingPrefab>>();
+        let levels: Vec<&str> = spawned
+            .iter(&app.world)
+            .map(|entity| entity.level.as_str())
+            .collect();
+        assert_eq!(levels, ["Cave", "Cave"]);
+    }
+}